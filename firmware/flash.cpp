@@ -19,6 +19,8 @@ static constexpr uint FLASH_CFG_OFFSET = FLASH_ROM_OFFSET - FLASH_SECTOR_SIZE;
 
 static constexpr uint CONFIG_VERSION = 0x00010009;
 
+extern char __flash_binary_end;
+
 const uint8_t *flash_rom_data = (uint8_t *)(XIP_BASE + FLASH_ROM_OFFSET);
 const Config *flash_config = (Config *)(XIP_BASE + FLASH_CFG_OFFSET);
 static_assert(sizeof(Config) <= FLASH_PAGE_SIZE);
@@ -104,6 +106,21 @@ void flash_save_rom()
     rom_service_start();
 }
 
+// Save the flash sectors covering offset..offset+len of the ROM
+void flash_save_rom_range(uint32_t offset, uint32_t len)
+{
+    uint32_t start = offset & ~(FLASH_SECTOR_SIZE - 1);
+    uint32_t end = MIN(offset + len + FLASH_SECTOR_SIZE - 1, ROM_SIZE) & ~(FLASH_SECTOR_SIZE - 1);
+    if (start >= end) return;
+
+    rom_service_stop();
+    uint32_t ints = save_and_disable_interrupts();
+    flash_range_erase(FLASH_ROM_OFFSET + start, end - start);
+    flash_range_program(FLASH_ROM_OFFSET + start, rom_get_buffer() + start, end - start);
+    restore_interrupts(ints);
+    rom_service_start();
+}
+
 uint32_t flash_load_rom()
 {
     uint32_t start_time = time_us_32();
@@ -118,3 +135,13 @@ uint32_t flash_load_rom()
 
     return flash_load_time;
 }
+
+uint32_t flash_rom_store_offset()
+{
+    return FLASH_ROM_OFFSET;
+}
+
+uint32_t flash_firmware_size()
+{
+    return (uint32_t)&__flash_binary_end - XIP_BASE;
+}
@@ -24,6 +24,10 @@ struct Config
 void flash_save_config(const Config *config);
 void flash_init_config(Config *config);
 void flash_save_rom();
+void flash_save_rom_range(uint32_t offset, uint32_t len);
 uint32_t flash_load_rom();
 
+uint32_t flash_rom_store_offset();
+uint32_t flash_firmware_size();
+
 #endif // FLASH_H
@@ -1,6 +1,8 @@
 #include "hardware/clocks.h"
 #include "hardware/gpio.h"
 #include "hardware/structs/syscfg.h"
+#include "hardware/structs/vreg_and_chip_reset.h"
+#include "hardware/watchdog.h"
 #include "pico/bootrom.h"
 #include <stdio.h>
 #include <unistd.h>
@@ -179,6 +181,43 @@ bool reset_from_string(const char *s, ResetLevel *level)
 
 uint32_t flash_load_time = 0;
 uint32_t system_status = 0;
+const char *reset_reason = "unknown";
+// Settings changed since they were last saved to flash
+bool settings_dirty = false;
+// Range of ROM writes not yet saved to flash, empty when start >= end
+uint32_t dirty_start = UINT32_MAX;
+uint32_t dirty_end = 0;
+// Host supplied hash of the current image, cleared whenever the image or its size changes
+static char rom_hash[17] = "";
+
+// CRC32 as used by zip, so the host can compare it with a local image
+static uint32_t crc32(const uint8_t *data, size_t len)
+{
+    uint32_t crc = 0xffffffff;
+    for (size_t i = 0; i < len; i++)
+    {
+        crc ^= data[i];
+        for (int bit = 0; bit < 8; bit++)
+        {
+            crc = (crc >> 1) ^ (0xedb88320 & -(crc & 1));
+        }
+    }
+    return ~crc;
+}
+
+void detect_reset_reason()
+{
+    uint32_t chip_reset = vreg_and_chip_reset_hw->chip_reset;
+
+    if (watchdog_caused_reboot())
+        reset_reason = "watchdog";
+    else if (chip_reset & VREG_AND_CHIP_RESET_CHIP_RESET_HAD_RUN_BITS)
+        reset_reason = "run_pin";
+    else if (chip_reset & VREG_AND_CHIP_RESET_CHIP_RESET_HAD_PSM_RESTART_BITS)
+        reset_reason = "debugger";
+    else if (chip_reset & VREG_AND_CHIP_RESET_CHIP_RESET_HAD_POR_BITS)
+        reset_reason = "power_on";
+}
 
 static Config config;
 
@@ -193,6 +232,16 @@ static const char *parameter_names[] = {
     "startup_time",
     "build_config",
     "build_version",
+    "max_payload",
+    "flash_size",
+    "rom_store_offset",
+    "firmware_size",
+    "uptime",
+    "reset_reason",
+    "max_rom_size",
+    "rom_dirty",
+    "rom_hash",
+    "protocol_version",
     nullptr
 };
 
@@ -202,6 +251,8 @@ bool set_parameter(const char *name, const char *value)
     {
         config.addr_mask = strtoul(value) & ADDR_MASK;
         configure_address_pins(config.addr_mask);
+        settings_dirty = true;
+        rom_hash[0] = 0;
         return true;
     }
     else if (streq(name, "name"))
@@ -213,6 +264,7 @@ bool set_parameter(const char *name, const char *value)
     else if (streq(name, "rom_name"))
     {
         strcpyz(config.rom_name, sizeof(config.name), value);
+        settings_dirty = true;
         return true;
     }
     else if (streq(name, "initial_reset"))
@@ -231,6 +283,11 @@ bool set_parameter(const char *name, const char *value)
             return true;
         }
     }
+    else if (streq(name, "rom_hash"))
+    {
+        strcpyz(rom_hash, sizeof(rom_hash), value);
+        return true;
+    }
     else if (streq(name, "reset"))
     {
         ResetLevel level;
@@ -299,6 +356,57 @@ bool get_parameter(const char *name, char *value, size_t value_size)
         strcpyz(value, value_size, PICOROM_FIRMWARE_VERSION);
         return true;
     }
+    else if (streq(name, "max_payload"))
+    {
+        snprintf(value, value_size, "%d", (int)MAX_RX_PKT_PAYLOAD);
+        return true;
+    }
+    else if (streq(name, "flash_size"))
+    {
+        snprintf(value, value_size, "0x%08x", FLASH_SIZE);
+        return true;
+    }
+    else if (streq(name, "rom_store_offset"))
+    {
+        snprintf(value, value_size, "0x%08x", flash_rom_store_offset());
+        return true;
+    }
+    else if (streq(name, "firmware_size"))
+    {
+        snprintf(value, value_size, "0x%08x", flash_firmware_size());
+        return true;
+    }
+    else if (streq(name, "uptime"))
+    {
+        snprintf(value, value_size, "%u", to_ms_since_boot(get_absolute_time()));
+        return true;
+    }
+    else if (streq(name, "reset_reason"))
+    {
+        strcpyz(value, value_size, reset_reason);
+        return true;
+    }
+    else if (streq(name, "max_rom_size"))
+    {
+        snprintf(value, value_size, "0x%08x", ROM_SIZE);
+        return true;
+    }
+    else if (streq(name, "rom_dirty"))
+    {
+        bool dirty = settings_dirty || dirty_start < dirty_end;
+        snprintf(value, value_size, "%d", dirty ? 1 : 0);
+        return true;
+    }
+    else if (streq(name, "protocol_version"))
+    {
+        snprintf(value, value_size, "%d", PROTOCOL_VERSION);
+        return true;
+    }
+    else if (streq(name, "rom_hash"))
+    {
+        strcpyz(value, value_size, rom_hash);
+        return true;
+    }
 
 
     return false;
@@ -306,6 +414,8 @@ bool get_parameter(const char *name, char *value, size_t value_size)
 
 int main()
 {
+    detect_reset_reason();
+
     set_sys_clock_khz(270000, true);
 
     flash_init_config(&config);
@@ -379,7 +489,10 @@ int main()
                             break;
                         }
                         memcpy(rom_get_buffer() + offset, req->payload, req->size);
+                        dirty_start = MIN(dirty_start, offset);
+                        dirty_end = MAX(dirty_end, offset + req->size);
                         rom_offset += req->size;
+                        rom_hash[0] = 0;
                         break;
                     }
 
@@ -396,15 +509,58 @@ int main()
                     {
                         flash_save_rom();
                         flash_save_config(&config);
+                        settings_dirty = false;
+                        dirty_start = UINT32_MAX;
+                        dirty_end = 0;
+                        pl_send_null(PacketType::CommitDone);
+                        break;
+                    }
+
+                    case PacketType::CommitRange:
+                    {
+                        uint32_t offset, len;
+                        memcpy(&offset, req->payload, 4);
+                        memcpy(&len, req->payload + 4, 4);
+                        if (offset > ROM_SIZE || len > ROM_SIZE - offset)
+                        {
+                            pl_send_error("Commit out of range", offset, len);
+                            break;
+                        }
+                        flash_save_rom_range(offset, len);
+                        flash_save_config(&config);
+                        settings_dirty = false;
+                        // Only clean if every unsaved write was inside the range
+                        if (dirty_start >= offset && dirty_end <= offset + len)
+                        {
+                            dirty_start = UINT32_MAX;
+                            dirty_end = 0;
+                        }
                         pl_send_null(PacketType::CommitDone);
                         break;
                     }
 
+                    case PacketType::Checksum:
+                    {
+                        uint32_t offset, len;
+                        memcpy(&offset, req->payload, 4);
+                        memcpy(&len, req->payload + 4, 4);
+                        if (offset > ROM_SIZE || len > ROM_SIZE - offset)
+                        {
+                            pl_send_error("Checksum out of range", offset, len);
+                            break;
+                        }
+                        uint32_t crc = crc32(rom_get_buffer() + offset, len);
+                        pl_send_payload(PacketType::ChecksumValue, &crc, sizeof(crc));
+                        break;
+                    }
+
                     case PacketType::CommsStart:
                     {
                         uint32_t addr;
                         memcpy(&addr, req->payload, 4);
                         comms_begin_session(addr, rom_get_buffer());
+                        // Comms traffic modifies the image
+                        rom_hash[0] = 0;
                         pl_send_debug("Comms Started", addr, 0);
                         break;
                     }
@@ -434,7 +590,7 @@ int main()
                             if (set_parameter((char *)req->payload, split + 1))
                             {
                                 Packet pkt;
-                                if (get_parameter((const char *)req->payload, (char *)pkt.payload, sizeof(pkt.payload)))
+                                if (get_parameter((const char *)req->payload, (char *)pkt.payload, MAX_PKT_PAYLOAD))
                                 {
                                     pkt.size = strlen((char *)pkt.payload);
                                     pkt.type = (uint8_t)PacketType::Parameter;
@@ -461,7 +617,7 @@ int main()
                     case PacketType::GetParameter:
                     {
                         Packet pkt;
-                        if (get_parameter((const char *)req->payload, (char *)pkt.payload, sizeof(pkt.payload)))
+                        if (get_parameter((const char *)req->payload, (char *)pkt.payload, MAX_PKT_PAYLOAD))
                         {
                             pkt.size = strlen((char *)pkt.payload);
                             pkt.type = (uint8_t)PacketType::Parameter;
@@ -15,6 +15,9 @@ enum class PacketType : uint8_t
 
     CommitFlash = 12,
     CommitDone = 13,
+    CommitRange = 14,
+    Checksum = 15,
+    ChecksumValue = 16,
 
     SetParameter = 20,
     GetParameter = 21,
@@ -32,14 +35,22 @@ enum class PacketType : uint8_t
     Debug = 0xff
 };
 
+// Raised when packets are added, reported as the protocol_version parameter.
+// Firmware without the parameter is version 1.
+static constexpr int PROTOCOL_VERSION = 3;
+
+// Largest payload sent to the host
 static constexpr size_t MAX_PKT_PAYLOAD = 30;
 
+// Largest payload accepted from the host, a full 64 byte USB packet
+static constexpr size_t MAX_RX_PKT_PAYLOAD = 62;
+
 struct Packet
 {
     uint8_t type;
     uint8_t size;
 
-    uint8_t payload[MAX_PKT_PAYLOAD];
+    uint8_t payload[MAX_RX_PKT_PAYLOAD];
 };
 
 void pl_send_null(PacketType type);
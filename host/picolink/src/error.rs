@@ -0,0 +1,49 @@
+use std::fmt;
+
+/// Failures callers may want to handle differently from other errors.
+/// They are returned inside `anyhow::Error`, so find them with `downcast_ref`.
+/// Failures of the port itself are `std::io::Error` or `serialport::Error`
+/// in the same chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PicoError {
+    /// No PicoROM with this name is connected
+    NotFound(String),
+    /// No PicoROMs are connected at all
+    NoDevices,
+    /// The device didn't respond in time
+    Timeout,
+    /// The connection to the device was lost
+    Disconnected,
+    /// The device's state doesn't match what was written to it
+    VerifyMismatch(String),
+    /// The device didn't receive every byte of an upload
+    UploadIncomplete,
+    /// The device refused to get or set this parameter, because it doesn't
+    /// have it or the value is invalid
+    ParameterRejected(String),
+    /// A port couldn't be opened because of its permissions
+    Permission(String),
+    /// Another process is using the device
+    Busy(String),
+}
+
+impl fmt::Display for PicoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PicoError::NotFound(name) => write!(f, "PicoROM '{}' not found.", name),
+            PicoError::NoDevices => write!(f, "No PicoROMs found."),
+            PicoError::Timeout => write!(f, "timeout"),
+            PicoError::Disconnected => write!(f, "Connection to the device was lost"),
+            PicoError::VerifyMismatch(msg) => write!(f, "{}", msg),
+            PicoError::UploadIncomplete => write!(f, "Upload did not complete."),
+            PicoError::ParameterRejected(name) => {
+                write!(f, "PicoROM rejected parameter '{}'", name)
+            }
+            PicoError::Permission(msg) => write!(f, "Permission denied: {}", msg),
+            PicoError::Busy(msg) => write!(f, "{} is in use by another process", msg),
+        }
+    }
+}
+
+impl std::error::Error for PicoError {}
@@ -1,15 +1,86 @@
 use anyhow::{anyhow, Result};
 use serialport::SerialPort;
 use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::{thread::sleep, time::Duration, time::Instant};
 
 use dirs::cache_dir;
+use log::{debug, log_enabled, trace, warn, Level};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 
+mod error;
+pub use error::*;
+
+mod lock;
+use lock::{lock_port, LockedTransport};
+
+mod tcp;
+pub use tcp::*;
+
+/// Shortest and longest sleeps between checks for incoming data
+const MIN_RECV_WAIT: Duration = Duration::from_micros(10);
+const MAX_RECV_WAIT: Duration = Duration::from_millis(1);
+
+/// How long to wait for a response over USB
+const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Response timeout for new links in milliseconds, see `set_default_response_timeout`
+static RESPONSE_TIMEOUT_MS: AtomicU64 = AtomicU64::new(DEFAULT_RESPONSE_TIMEOUT.as_millis() as u64);
+
+/// How long to wait for a commit or checksum, or the response timeout if longer
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Set the response timeout of links opened from now on, including the ones
+/// opened while enumerating and finding devices. Slow or heavily loaded hosts
+/// may need longer than the default of 100ms. See `PicoLink::set_response_timeout`.
+pub fn set_default_response_timeout(timeout: Duration) {
+    RESPONSE_TIMEOUT_MS.store(timeout.as_millis() as u64, Ordering::Relaxed);
+}
+
+fn default_response_timeout() -> Duration {
+    Duration::from_millis(RESPONSE_TIMEOUT_MS.load(Ordering::Relaxed))
+}
+
+/// How long `poll_comms` waits for more data before deciding nothing else is coming
+const COMMS_DRAIN_WAIT: Duration = Duration::from_micros(500);
+
+/// Write packets sent to the port in one write during uploads. The device
+/// reads a stream of packets, so they don't need a write each.
+const WRITE_BATCH: usize = 16;
+
+/// Wait before the first retry of a timed out send, doubled for each retry after
+const SEND_RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Sent by the firmware when the host connects
+const GREETING: &[u8] = b"PicoROM Hello";
+
+/// Most bytes to skip while looking for the greeting
+const MAX_PREAMBLE: usize = 256;
+
+/// Baud rate requested when opening a port
+pub const DEFAULT_BAUD: u32 = 9600;
+
+/// ROM buffer size of firmware that doesn't report `max_rom_size`
+const DEFAULT_MAX_ROM_SIZE: usize = 256 * 1024;
+
+/// Protocol version that added CommitRange
+const COMMIT_RANGE_VERSION: u32 = 2;
+
+/// Protocol version that added Checksum
+const CHECKSUM_VERSION: u32 = 3;
+
+/// Largest packet payload understood by every firmware version
+const DEFAULT_PAYLOAD: usize = 30;
+
+/// Largest Write payload that fits in a single 64 byte USB packet
+const MAX_PAYLOAD: usize = 62;
+
 #[repr(u8)]
 #[derive(FromPrimitive, Debug)]
 enum PacketKind {
@@ -22,6 +93,9 @@ enum PacketKind {
 
     CommitFlash = 12,
     CommitDone = 13,
+    CommitRange = 14,
+    Checksum = 15,
+    ChecksumValue = 16,
 
     ParameterSet = 20,
     ParameterGet = 21,
@@ -39,13 +113,49 @@ enum PacketKind {
     Debug = 0xff,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ResetLevel {
     High,
     Low,
     Z,
 }
 
+impl ResetLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResetLevel::Low => "low",
+            ResetLevel::High => "high",
+            ResetLevel::Z => "z",
+        }
+    }
+}
+
+impl fmt::Display for ResetLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Accepts the same spellings as the firmware: high/h, low/l and z
+impl FromStr for ResetLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "high" | "h" => Ok(ResetLevel::High),
+            "low" | "l" => Ok(ResetLevel::Low),
+            "z" => Ok(ResetLevel::Z),
+            _ => Err(anyhow!(
+                "Invalid reset level '{}', expected high, low or z",
+                s
+            )),
+        }
+    }
+}
+
+/// Parameters whose values are reset levels
+const RESET_PARAMETERS: [&str; 3] = ["reset", "initial_reset", "default_reset"];
+
 #[derive(Clone, Debug)]
 pub enum ReqPacket {
     PointerSet(u32),
@@ -53,6 +163,8 @@ pub enum ReqPacket {
     Write(Vec<u8>),
     Read,
     CommitFlash,
+    CommitRange(u32, u32),
+    Checksum(u32, u32),
     CommsStart(u32),
     CommsEnd,
     CommsData(Vec<u8>),
@@ -63,6 +175,13 @@ pub enum ReqPacket {
     ParameterSet(String, String),
 }
 
+fn hex_string(data: &[u8]) -> String {
+    data.iter()
+        .map(|x| format!("{:02x}", x))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
 fn zstring(s: String) -> Vec<u8> {
     let mut v = s.as_bytes().to_vec();
     v.push(0u8);
@@ -79,6 +198,14 @@ impl ReqPacket {
             ReqPacket::Write(data) => (PacketKind::Write, data),
             ReqPacket::Read => (PacketKind::Read, vec![]),
             ReqPacket::CommitFlash => (PacketKind::CommitFlash, vec![]),
+            ReqPacket::CommitRange(addr, len) => (
+                PacketKind::CommitRange,
+                [addr.to_le_bytes(), len.to_le_bytes()].concat(),
+            ),
+            ReqPacket::Checksum(addr, len) => (
+                PacketKind::Checksum,
+                [addr.to_le_bytes(), len.to_le_bytes()].concat(),
+            ),
             ReqPacket::CommsStart(addr) => (PacketKind::CommsStart, addr.to_le_bytes().to_vec()),
             ReqPacket::CommsEnd => (PacketKind::CommsEnd, vec![]),
             ReqPacket::CommsData(data) => (PacketKind::CommsData, data),
@@ -87,17 +214,34 @@ impl ReqPacket {
             ReqPacket::ParameterQuery(None) => (PacketKind::ParameterQuery, vec![]),
             ReqPacket::ParameterQuery(Some(x)) => (PacketKind::ParameterQuery, zstring(x)),
             ReqPacket::ParameterGet(param) => (PacketKind::ParameterGet, zstring(param)),
-            ReqPacket::ParameterSet(param, value) => (
-                PacketKind::ParameterSet,
-                zstring(format!("{},{}", param, value)),
-            ),
+            // The firmware splits at the first comma, so the value may contain commas but the name can't
+            ReqPacket::ParameterSet(param, value) => {
+                if param.contains(',') {
+                    return Err(anyhow!("Parameter name '{}' cannot contain a comma", param));
+                }
+                if param.contains('\0') || value.contains('\0') {
+                    return Err(anyhow!(
+                        "Parameter '{}' cannot contain NUL characters",
+                        param
+                    ));
+                }
+                (
+                    PacketKind::ParameterSet,
+                    zstring(format!("{},{}", param, value)),
+                )
+            }
+        };
+
+        let limit = match kind {
+            PacketKind::Write => MAX_PAYLOAD,
+            _ => DEFAULT_PAYLOAD,
         };
 
-        if payload.len() > 30 {
+        if payload.len() > limit {
             return Err(anyhow!("{:?} request packet payload too large", self));
         }
 
-        let mut data = Vec::with_capacity(32);
+        let mut data = Vec::with_capacity(payload.len() + 2);
         data.push(kind as u8);
         data.push(payload.len() as u8);
         data.extend(payload);
@@ -110,6 +254,7 @@ pub enum RespPacket {
     PointerCur(u32),
     ReadData(Vec<u8>),
     CommitDone,
+    Checksum(u32),
     CommsData(Vec<u8>),
     Parameter(String),
     ParameterError,
@@ -118,50 +263,185 @@ pub enum RespPacket {
     Debug(String, u32, u32),
 }
 
+/// The byte stream a `PicoLink` runs the protocol over.
+/// Implemented for serial ports, and by test doubles that stand in for a device.
+pub trait Transport: Read + Write + Send {
+    /// Number of received bytes that can be read without blocking
+    fn bytes_to_read(&mut self) -> Result<u32>;
+}
+
+impl Transport for Box<dyn SerialPort> {
+    fn bytes_to_read(&mut self) -> Result<u32> {
+        Ok(SerialPort::bytes_to_read(self.as_ref())?)
+    }
+}
+
 pub struct PicoLink {
-    port: Box<dyn SerialPort>,
-    debug: bool,
+    port: Box<dyn Transport>,
+    chunk_size: Option<usize>,
+    response_timeout: Duration,
+    /// Times a timed out write is retried before giving up
+    send_retries: u32,
+    comms_active: bool,
+    /// Comms data that arrived while waiting for another response
+    comms_buffer: Vec<u8>,
     pub path: String,
 }
 
 struct RawPacket {
     kind: PacketKind,
     size: usize,
-    payload: [u8; 30],
+    payload: [u8; DEFAULT_PAYLOAD],
 }
 
 impl PicoLink {
-    pub fn open(port_path: &str, debug: bool) -> Result<PicoLink> {
-        let mut port = serialport::new(port_path, 9600)
-            .timeout(std::time::Duration::from_millis(500))
-            .open()?;
+    pub fn open(port_path: &str) -> Result<PicoLink> {
+        PicoLink::open_with_baud(port_path, DEFAULT_BAUD)
+    }
 
-        let expected = "PicoROM Hello".as_bytes();
-        let mut preamble = Vec::new();
+    /// Open a port, requesting a specific baud rate. The RP2040's USB serial
+    /// ignores the rate, but some host drivers care about it.
+    /// The port stays locked against other processes until the link is dropped.
+    pub fn open_with_baud(port_path: &str, baud: u32) -> Result<PicoLink> {
+        let lock = lock_port(port_path)?;
+        let mut port = serialport::new(port_path, baud)
+            .timeout(std::time::Duration::from_millis(500))
+            .open()
+            .map_err(|e| match e.kind() {
+                serialport::ErrorKind::Io(std::io::ErrorKind::PermissionDenied) => {
+                    anyhow::Error::new(PicoError::Permission(port_path.to_string()))
+                }
+                _ => e.into(),
+            })?;
 
         port.write_data_terminal_ready(true)?;
 
-        while preamble.len() < expected.len() && !preamble.ends_with(&expected) {
+        let port = LockedTransport {
+            inner: port,
+            _lock: lock,
+        };
+        PicoLink::connect(port, port_path)
+    }
+
+    /// Wait for the "PicoROM Hello" greeting on a newly opened transport, then
+    /// run the protocol over it. Anything received before the greeting is skipped.
+    pub fn connect<T: Transport + 'static>(mut transport: T, path: &str) -> Result<PicoLink> {
+        let mut preamble = Vec::new();
+        while !preamble.ends_with(GREETING) {
+            if preamble.len() > MAX_PREAMBLE {
+                return Err(anyhow!("{}: no greeting from device", path));
+            }
             let mut buf = [0u8];
-            port.read_exact(&mut buf)?;
+            transport.read_exact(&mut buf)?;
             preamble.push(buf[0]);
         }
 
-        Ok(PicoLink {
-            port,
-            debug,
-            path: port_path.to_string(),
-        })
+        Ok(PicoLink::from_transport(transport, path))
+    }
+
+    /// Run the protocol over a transport that is already past the greeting,
+    /// see `connect`. `path` is only used to identify it in logs.
+    pub fn from_transport<T: Transport + 'static>(transport: T, path: &str) -> PicoLink {
+        PicoLink {
+            port: Box::new(transport),
+            chunk_size: None,
+            response_timeout: default_response_timeout(),
+            send_retries: 0,
+            comms_active: false,
+            comms_buffer: Vec::new(),
+            path: path.to_string(),
+        }
+    }
+
+    /// Give up the protocol and take back the underlying transport
+    pub fn into_transport(self) -> Box<dyn Transport> {
+        self.port
+    }
+
+    /// How long to wait for the response to a request. Transports with more
+    /// latency than USB need longer than the default.
+    pub fn set_response_timeout(&mut self, timeout: Duration) {
+        self.response_timeout = timeout;
+    }
+
+    /// How many times to retry a write that times out, e.g. on a busy USB hub,
+    /// backing off between tries. The default of 0 fails on the first timeout.
+    /// Other errors, such as the device disconnecting, are never retried.
+    pub fn set_send_retries(&mut self, retries: u32) {
+        self.send_retries = retries;
+    }
+
+    /// Override the number of bytes sent in each Write packet
+    pub fn set_chunk_size(&mut self, size: usize) -> Result<()> {
+        if size == 0 || size > MAX_PAYLOAD {
+            return Err(anyhow!("Chunk size must be between 1 and {}", MAX_PAYLOAD));
+        }
+        self.chunk_size = Some(size);
+        Ok(())
+    }
+
+    /// Number of bytes sent in each Write packet
+    /// Firmware that reports `max_payload` can accept larger packets,
+    /// older firmware is limited to the original 30 bytes.
+    pub fn chunk_size(&mut self) -> usize {
+        if let Some(size) = self.chunk_size {
+            return size;
+        }
+
+        let size = self
+            .get_parameter("max_payload")
+            .ok()
+            .and_then(|x| x.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_PAYLOAD)
+            .clamp(1, MAX_PAYLOAD);
+
+        self.chunk_size = Some(size);
+        size
     }
 
     pub fn send(&mut self, packet: ReqPacket) -> Result<()> {
         self.recv_flush()?;
 
         let data = packet.encode()?;
+        self.write_packet(&data)
+    }
 
-        //println!(">>> {} {} {:?}", data[0], data[1], &data[2..]);
+    /// Write one or more encoded packets to the port
+    fn write_packet(&mut self, data: &[u8]) -> Result<()> {
+        if log_enabled!(Level::Trace) {
+            let mut rest = data;
+            while rest.len() >= 2 {
+                let kind: Option<PacketKind> = FromPrimitive::from_u8(rest[0]);
+                let end = (2 + rest[1] as usize).min(rest.len());
+                trace!(
+                    "{}: >>> {:?} [{}] {}",
+                    self.path,
+                    kind,
+                    rest[1],
+                    hex_string(&rest[2..end])
+                );
+                rest = &rest[end..];
+            }
+        }
 
-        self.port.write_all(&data)?;
+        // Like write_all, but a timeout resumes from the first unsent byte so
+        // a retried packet is never duplicated
+        let mut written = 0;
+        let mut retries = 0;
+        while written < data.len() {
+            match self.port.write(&data[written..]) {
+                Ok(0) => return Err(PicoError::Disconnected.into()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == ErrorKind::TimedOut && retries < self.send_retries => {
+                    let backoff = SEND_RETRY_BACKOFF * 2u32.pow(retries);
+                    debug!("{}: send timed out, retrying in {:?}", self.path, backoff);
+                    sleep(backoff);
+                    retries += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
         Ok(())
     }
 
@@ -171,18 +451,23 @@ impl PicoLink {
     fn recv_raw(&mut self, deadline: Instant) -> Result<Option<RawPacket>> {
         let port = &mut self.port;
 
+        // Back off while waiting so long waits don't spin, without adding
+        // latency to the quick responses most requests get
+        let mut wait = MIN_RECV_WAIT;
         while port.bytes_to_read()? < 2 {
-            if Instant::now() > deadline {
+            let now = Instant::now();
+            if now > deadline {
                 return Ok(None);
             }
-            sleep(Duration::from_micros(10));
+            sleep(wait.min(deadline - now));
+            wait = (wait * 2).min(MAX_RECV_WAIT);
         }
 
         let mut data = [0u8; 32];
         port.read_exact(&mut data[0..2])?;
         let size = data[1] as usize;
 
-        if size > 30 {
+        if size > DEFAULT_PAYLOAD {
             return Err(anyhow!("Packet payload too large: {}", size));
         }
 
@@ -214,7 +499,13 @@ impl PicoLink {
         let pkt = pkt.unwrap();
         let payload = &pkt.payload[0..pkt.size];
 
-        //println!("<<< {:?} {} {:?}", pkt.kind, pkt.size, payload);
+        trace!(
+            "{}: <<< {:?} [{}] {}",
+            self.path,
+            pkt.kind,
+            pkt.size,
+            hex_string(payload)
+        );
 
         match pkt.kind {
             PacketKind::Debug => {
@@ -243,10 +534,14 @@ impl PicoLink {
             }
             PacketKind::ReadData => Ok(Some(RespPacket::ReadData(payload.to_vec()))),
             PacketKind::CommitDone => Ok(Some(RespPacket::CommitDone)),
+            PacketKind::ChecksumValue => {
+                let arr = payload.try_into().unwrap_or_default();
+                Ok(Some(RespPacket::Checksum(u32::from_le_bytes(arr))))
+            }
             PacketKind::CommsData => Ok(Some(RespPacket::CommsData(payload.to_vec()))),
             PacketKind::ParameterError => Ok(Some(RespPacket::ParameterError)),
             PacketKind::Parameter => Ok(Some(RespPacket::Parameter(
-                String::from_utf8_lossy(&payload).to_string(),
+                String::from_utf8_lossy(payload).to_string(),
             ))),
 
             x => Err(anyhow::format_err!("Unexpected packet kind: {:?}", x)),
@@ -254,27 +549,37 @@ impl PicoLink {
     }
 
     fn recv_flush(&mut self) -> Result<()> {
-        let deadline = Instant::now();
+        self.recv_drain(Duration::ZERO)
+    }
 
-        while let Some(pkt) = self.recv(deadline)? {
+    /// Handle incoming packets until none arrives for `quiet`
+    fn recv_drain(&mut self, quiet: Duration) -> Result<()> {
+        while let Some(pkt) = self.recv(Instant::now() + quiet)? {
             match pkt {
-                RespPacket::Debug(msg, v0, v1) => {
-                    if self.debug {
-                        eprintln!("DEBUG: '{}' [0x{:x}, 0x{:x}]", msg, v0, v1);
-                    }
-                }
-                RespPacket::Error(msg, v0, v1) => {
-                    if self.debug {
-                        eprintln!("ERROR: '{}' [0x{:x}, 0x{:x}]", msg, v0, v1);
-                    }
-                }
-                _ => {}
+                RespPacket::CommsData(data) => self.comms_buffer.extend_from_slice(&data),
+                x => self.report(&x),
             }
         }
 
         Ok(())
     }
 
+    /// Log device debug and error messages
+    fn report(&self, pkt: &RespPacket) {
+        match pkt {
+            RespPacket::Debug(msg, v0, v1) => {
+                debug!("{}: device: '{}' [0x{:x}, 0x{:x}]", self.path, msg, v0, v1);
+            }
+            RespPacket::Error(msg, v0, v1) => {
+                warn!(
+                    "{}: device error: '{}' [0x{:x}, 0x{:x}]",
+                    self.path, msg, v0, v1
+                );
+            }
+            _ => {}
+        }
+    }
+
     pub fn recv_forever(&mut self) -> Result<()> {
         loop {
             self.recv_flush()?;
@@ -290,40 +595,37 @@ impl PicoLink {
 
         while let Some(pkt) = self.recv(deadline)? {
             match pkt {
-                RespPacket::Debug(msg, v0, v1) => {
-                    if self.debug {
-                        eprintln!("DEBUG: '{}' [0x{:x}, 0x{:x}]", msg, v0, v1);
-                    }
-                }
-                RespPacket::Error(msg, v0, v1) => {
-                    if self.debug {
-                        eprintln!("ERROR: '{}' [0x{:x}, 0x{:x}]", msg, v0, v1);
-                    }
-                }
+                RespPacket::Debug(..) | RespPacket::Error(..) => self.report(&pkt),
+                RespPacket::CommsData(data) => self.comms_buffer.extend_from_slice(&data),
                 x => {
-                    let res = f(x);
-                    if res.is_some() {
-                        return Ok(res.unwrap());
+                    if let Some(res) = f(x) {
+                        return Ok(res);
                     }
                 }
             }
         }
 
-        Err(anyhow!("timeout"))
+        Err(PicoError::Timeout.into())
     }
 
     pub fn recv_until<T, F>(&mut self, f: F) -> Result<T>
     where
         F: Fn(RespPacket) -> Option<T>,
     {
-        self.recv_until_with_timeout(f, Duration::from_millis(100))
+        self.recv_until_with_timeout(f, self.response_timeout)
     }
 
     pub fn get_ident(&mut self) -> Result<String> {
         self.get_parameter("name")
     }
 
-    pub fn set_ident(&mut self, name: &str) -> Result<()> {
+    /// Rename the device. Returns false without writing anything if it
+    /// already has that name.
+    pub fn set_ident(&mut self, name: &str) -> Result<bool> {
+        if self.get_ident()? == name {
+            return Ok(false);
+        }
+
         let name_check = self.set_parameter("name", name)?;
         if name != name_check {
             Err(anyhow!(
@@ -332,7 +634,7 @@ impl PicoLink {
                 name_check
             ))
         } else {
-            Ok(())
+            Ok(true)
         }
     }
 
@@ -340,7 +642,9 @@ impl PicoLink {
         self.send(ReqPacket::ParameterGet(name.to_string()))?;
         self.recv_until(|pkt| match pkt {
             RespPacket::Parameter(x) => Some(Ok(x)),
-            RespPacket::ParameterError => Some(Err(anyhow!("Could not get parameter '{}'", name))),
+            RespPacket::ParameterError => {
+                Some(Err(PicoError::ParameterRejected(name.into()).into()))
+            }
             _ => None,
         })?
     }
@@ -358,7 +662,7 @@ impl PicoLink {
                 _ => None,
             })?;
             let parameter = parameter?;
-            if parameter.len() > 0 {
+            if !parameter.is_empty() {
                 prev = Some(parameter.clone());
                 parameters.push(parameter);
             } else {
@@ -368,23 +672,58 @@ impl PicoLink {
     }
 
     pub fn set_parameter(&mut self, name: &str, value: &str) -> Result<String> {
-        self.send(ReqPacket::ParameterSet(name.to_string(), value.to_string()))?;
+        // Validate reset levels here rather than sending garbage to the device
+        let value = if RESET_PARAMETERS.contains(&name) {
+            value.parse::<ResetLevel>()?.to_string()
+        } else {
+            value.to_string()
+        };
+
+        self.send(ReqPacket::ParameterSet(name.to_string(), value))?;
         self.recv_until(|pkt| match pkt {
             RespPacket::Parameter(x) => Some(Ok(x)),
-            RespPacket::ParameterError => Some(Err(anyhow!("Could not set parameter '{}'", name))),
+            RespPacket::ParameterError => {
+                Some(Err(PicoError::ParameterRejected(name.into()).into()))
+            }
             _ => None,
         })?
     }
 
-    pub fn upload<F>(&mut self, data: &[u8], addr_mask: u32, f: F) -> Result<()>
+    /// Write a full ROM image starting at address 0
+    /// The address mask is left unchanged, see `set_addr_mask`.
+    pub fn upload<F>(&mut self, data: &[u8], f: F) -> Result<()>
+    where
+        F: Fn(usize),
+    {
+        self.upload_to(0, data, f)
+    }
+
+    pub fn upload_to<F>(&mut self, addr: u32, data: &[u8], f: F) -> Result<()>
     where
         F: Fn(usize),
     {
-        self.send(ReqPacket::PointerSet(0))?;
+        let chunk_size = self.chunk_size();
 
-        for chunk in data.chunks(30) {
-            f(chunk.len());
-            self.send(ReqPacket::Write(chunk.to_vec()))?;
+        debug!(
+            "{}: writing {} bytes at 0x{:x} in {} byte chunks",
+            self.path,
+            data.len(),
+            addr,
+            chunk_size
+        );
+
+        self.send(ReqPacket::PointerSet(addr))?;
+
+        // Writes have no response, so several are sent at once to save a
+        // round trip through the OS and USB stack for each one
+        for batch in data.chunks(chunk_size * WRITE_BATCH) {
+            let mut packets = Vec::with_capacity(batch.len() + 2 * WRITE_BATCH);
+            for chunk in batch.chunks(chunk_size) {
+                packets.extend(ReqPacket::Write(chunk.to_vec()).encode()?);
+            }
+            self.recv_flush()?;
+            self.write_packet(&packets)?;
+            f(batch.len());
         }
 
         self.send(ReqPacket::PointerGet)?;
@@ -394,41 +733,143 @@ impl PicoLink {
             _ => None,
         })?;
 
-        if cur != data.len() as u32 {
-            return Err(anyhow!("Upload did not complete."));
+        if cur.wrapping_sub(addr) != data.len() as u32 {
+            return Err(PicoError::UploadIncomplete.into());
         }
 
-        self.set_parameter("addr_mask", &format!("0x{:x}", addr_mask))?;
+        Ok(())
+    }
 
+    pub fn set_addr_mask(&mut self, addr_mask: u32) -> Result<()> {
+        self.set_parameter("addr_mask", &format!("0x{:x}", addr_mask))?;
         Ok(())
     }
 
-    pub fn upload_to<F>(&mut self, addr: u32, data: &[u8], f: F) -> Result<()>
+    pub fn download<F>(&mut self, size: usize, f: F) -> Result<Vec<u8>>
     where
         F: Fn(usize),
     {
-        self.send(ReqPacket::PointerSet(addr))?;
+        self.download_from(0, size, f)
+    }
+
+    pub fn download_from<F>(&mut self, addr: u32, size: usize, f: F) -> Result<Vec<u8>>
+    where
+        F: Fn(usize),
+    {
+        Ok(self.read_chunks(addr, size, f)?.0)
+    }
 
-        for chunk in data.chunks(30) {
-            f(chunk.len());
-            self.send(ReqPacket::Write(chunk.to_vec()))?;
+    /// Read `len` bytes of ROM at `offset`, such as state a target keeps in a
+    /// window of the ROM. Unlike `download_from`, the range is checked against
+    /// the ROM buffer first and the device's pointer afterwards, so a lost or
+    /// stale chunk is an error rather than shifted data.
+    pub fn read_range<F>(&mut self, offset: u32, len: usize, f: F) -> Result<Vec<u8>>
+    where
+        F: Fn(usize),
+    {
+        let max = self.max_rom_size() as u64;
+        if offset as u64 + len as u64 > max {
+            return Err(anyhow!(
+                "Read range 0x{:x}-0x{:x} is outside the ROM buffer (0x{:x} bytes)",
+                offset,
+                offset as u64 + len as u64,
+                max
+            ));
         }
 
-        self.send(ReqPacket::PointerGet)?;
+        let (data, received) = self.read_chunks(offset, len, f)?;
 
+        self.send(ReqPacket::PointerGet)?;
         let cur = self.recv_until(|x| match x {
             RespPacket::PointerCur(x) => Some(x),
             _ => None,
         })?;
+        let expected = offset.wrapping_add(received as u32);
+        if cur != expected {
+            return Err(anyhow!(
+                "{}: read ended at 0x{:x} instead of 0x{:x}",
+                self.path,
+                cur,
+                expected
+            ));
+        }
+
+        Ok(data)
+    }
 
-        if (cur - addr) != data.len() as u32 {
-            return Err(anyhow!("Upload did not complete."));
+    /// Read `size` bytes at `addr`, returning them along with how many bytes
+    /// the device sent, which can be more as its last chunk isn't truncated
+    fn read_chunks<F>(&mut self, addr: u32, size: usize, f: F) -> Result<(Vec<u8>, usize)>
+    where
+        F: Fn(usize),
+    {
+        debug!("{}: reading {} bytes at 0x{:x}", self.path, size, addr);
+
+        self.send(ReqPacket::PointerSet(addr))?;
+
+        let mut data = Vec::with_capacity(size);
+        let mut received = 0;
+
+        while data.len() < size {
+            self.send(ReqPacket::Read)?;
+
+            let chunk = self.recv_until(|x| match x {
+                RespPacket::ReadData(x) => Some(x),
+                _ => None,
+            })?;
+
+            if chunk.is_empty() {
+                return Err(anyhow!(
+                    "Download ended early at 0x{:x}",
+                    addr as usize + data.len()
+                ));
+            }
+
+            received += chunk.len();
+            let len = chunk.len().min(size - data.len());
+            f(len);
+            data.extend_from_slice(&chunk[..len]);
         }
 
-        Ok(())
+        Ok((data, received))
+    }
+
+    /// Download the whole ROM image, sized by the current address mask
+    pub fn download_full<F>(&mut self, f: F) -> Result<Vec<u8>>
+    where
+        F: Fn(usize),
+    {
+        let size = self.rom_size()?;
+        self.download(size, f)
+    }
+
+    pub fn get_addr_mask(&mut self) -> Result<u32> {
+        self.get_parameter_u32("addr_mask")
+    }
+
+    /// Size in bytes of the largest ROM the device can emulate
+    pub fn max_rom_size(&mut self) -> usize {
+        self.get_parameter_u32("max_rom_size")
+            .map_or(DEFAULT_MAX_ROM_SIZE, |x| x as usize)
+    }
+
+    /// Size in bytes of the ROM currently being emulated
+    pub fn rom_size(&mut self) -> Result<usize> {
+        Ok(self.get_addr_mask()? as usize + 1)
+    }
+
+    /// Get a numeric parameter, reported as either 0x prefixed hex or decimal
+    pub fn get_parameter_u32(&mut self, name: &str) -> Result<u32> {
+        let value = self.get_parameter(name)?;
+        let parsed = match value.strip_prefix("0x") {
+            Some(digits) => u32::from_str_radix(digits, 16),
+            None => value.parse(),
+        };
+        parsed.map_err(|_| anyhow!("Invalid {} '{}'", name, value))
     }
 
     pub fn commit_rom(&mut self) -> Result<()> {
+        debug!("{}: committing ROM to flash", self.path);
         self.send(ReqPacket::CommitFlash)?;
 
         self.recv_until_with_timeout(
@@ -436,10 +877,92 @@ impl PicoLink {
                 RespPacket::CommitDone => Some(()),
                 _ => None,
             },
-            Duration::from_secs(5),
+            COMMIT_TIMEOUT.max(self.response_timeout),
+        )
+    }
+
+    /// Protocol version the firmware speaks. Firmware that doesn't report it is version 1.
+    pub fn protocol_version(&mut self) -> u32 {
+        self.get_parameter_u32("protocol_version").unwrap_or(1)
+    }
+
+    /// Commit the flash sectors covering `len` bytes at `addr`, along with the
+    /// device's settings. Firmware that can't commit a range commits the whole ROM.
+    pub fn commit_range(&mut self, addr: u32, len: u32) -> Result<()> {
+        if self.protocol_version() < COMMIT_RANGE_VERSION {
+            debug!("{}: firmware can't commit a range", self.path);
+            return self.commit_rom();
+        }
+
+        debug!(
+            "{}: committing 0x{:x} bytes at 0x{:x} to flash",
+            self.path, len, addr
+        );
+        self.send(ReqPacket::CommitRange(addr, len))?;
+        self.recv_until_with_timeout(
+            |x| match x {
+                RespPacket::CommitDone => Some(()),
+                _ => None,
+            },
+            COMMIT_TIMEOUT.max(self.response_timeout),
+        )
+    }
+
+    /// Whether the firmware can compute checksums with `checksum`
+    pub fn can_checksum(&mut self) -> bool {
+        self.protocol_version() >= CHECKSUM_VERSION
+    }
+
+    /// CRC32 (IEEE, as used by zip and crc32fast) of `len` bytes of ROM at
+    /// `addr`, computed by the device so the data doesn't have to be read back
+    pub fn checksum(&mut self, addr: u32, len: u32) -> Result<u32> {
+        if !self.can_checksum() {
+            return Err(anyhow!("{}: firmware can't compute checksums", self.path));
+        }
+        let max = self.max_rom_size() as u64;
+        if addr as u64 + len as u64 > max {
+            return Err(anyhow!(
+                "Checksum range 0x{:x}-0x{:x} is outside the ROM buffer (0x{:x} bytes)",
+                addr,
+                addr as u64 + len as u64,
+                max
+            ));
+        }
+
+        debug!(
+            "{}: checksum of 0x{:x} bytes at 0x{:x}",
+            self.path, len, addr
+        );
+        self.send(ReqPacket::Checksum(addr, len))?;
+        self.recv_until_with_timeout(
+            |x| match x {
+                RespPacket::Checksum(crc) => Some(crc),
+                _ => None,
+            },
+            COMMIT_TIMEOUT.max(self.response_timeout),
         )
     }
 
+    /// Whether the ROM image or its settings have changed since they were last
+    /// stored in flash, or None if the firmware doesn't report it
+    pub fn rom_dirty(&mut self) -> Option<bool> {
+        self.get_parameter_u32("rom_dirty").ok().map(|x| x != 0)
+    }
+
+    /// Commit the ROM to flash, then check the device reports nothing left
+    /// unsaved. Firmware that doesn't report it is trusted.
+    pub fn commit_rom_verified(&mut self) -> Result<()> {
+        self.commit_rom()?;
+        if self.rom_dirty() == Some(true) {
+            return Err(PicoError::VerifyMismatch(format!(
+                "{}: ROM still has unsaved changes after commit",
+                self.path
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
     pub fn identify(&mut self) -> Result<()> {
         self.send(ReqPacket::Identify)?;
         Ok(())
@@ -451,57 +974,141 @@ impl PicoLink {
     }
 
     pub fn reset(&mut self, level: ResetLevel) -> Result<()> {
-        let rst = match level {
-            ResetLevel::Low => "low",
-            ResetLevel::High => "high",
-            ResetLevel::Z => "z",
-        };
-        self.set_parameter("reset", rst)?;
+        self.set_parameter("reset", level.as_str())?;
+        Ok(())
+    }
+
+    /// Start two-way communications using the comms registers at `addr`.
+    /// The reset line is not touched, so the target can be held or pulsed independently.
+    /// Other requests can be made while comms is active. Comms data that arrives
+    /// while waiting for their responses is kept for the next `poll_comms`.
+    pub fn start_comms(&mut self, addr: u32) -> Result<()> {
+        self.send(ReqPacket::CommsStart(addr))?;
+        self.comms_active = true;
+        self.comms_buffer.clear();
+        Ok(())
+    }
+
+    pub fn end_comms(&mut self) -> Result<()> {
+        self.send(ReqPacket::CommsEnd)?;
+        self.comms_active = false;
         Ok(())
     }
 
+    /// Like `poll_comms(None)`, but waits up to `timeout` for data to arrive
+    /// instead of returning immediately when there is none
+    pub fn poll_comms_blocking(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        if !self.comms_active {
+            return Err(anyhow!("Comms not active"));
+        }
+
+        let deadline = Instant::now() + timeout;
+        while self.comms_buffer.is_empty() {
+            match self.recv(deadline)? {
+                Some(RespPacket::CommsData(data)) => self.comms_buffer.extend_from_slice(&data),
+                Some(pkt) => self.report(&pkt),
+                None => break,
+            }
+        }
+        self.recv_flush()?;
+
+        Ok(std::mem::take(&mut self.comms_buffer))
+    }
+
+    /// Discard any comms data received so far without ending comms,
+    /// e.g. to resynchronize with the target after an error
+    pub fn comms_flush(&mut self) -> Result<()> {
+        if !self.comms_active {
+            return Err(anyhow!("Comms not active"));
+        }
+
+        self.recv_flush()?;
+        self.comms_buffer.clear();
+        Ok(())
+    }
+
+    pub fn comms_active(&self) -> bool {
+        self.comms_active
+    }
+
+    /// Send `outgoing` and return all comms data received since the last poll
     pub fn poll_comms(&mut self, outgoing: Option<Vec<u8>>) -> Result<Vec<u8>> {
-        let mut incoming = Vec::new();
+        if !self.comms_active {
+            return Err(anyhow!("Comms not active"));
+        }
+
         if let Some(outgoing) = outgoing {
-            for chunk in outgoing.chunks(30) {
-                while let Some(pkt) = self.recv(Instant::now())? {
-                    match pkt {
-                        RespPacket::CommsData(data) => {
-                            incoming.extend_from_slice(&data);
-                        }
-                        _ => {}
-                    }
-                }
+            for chunk in outgoing.chunks(DEFAULT_PAYLOAD) {
+                self.recv_flush()?;
                 let pkt = ReqPacket::CommsData(chunk.to_vec()).encode()?;
-                self.port.write_all(&pkt)?;
+                self.write_packet(&pkt)?;
             }
         }
-        while let Some(pkt) = self.recv(Instant::now())? {
-            match pkt {
-                RespPacket::CommsData(data) => {
-                    incoming.extend_from_slice(&data);
-                }
-                _ => {}
-            }
+        // A zero timeout can miss packets that are still arriving, so wait
+        // briefly for a real gap before returning
+        self.recv_drain(COMMS_DRAIN_WAIT)?;
+
+        Ok(std::mem::take(&mut self.comms_buffer))
+    }
+}
+
+/// A PicoROM found during enumeration
+#[derive(Clone, Debug)]
+pub struct PicoInfo {
+    /// Device name, as reported by the `name` parameter
+    pub name: String,
+    /// Serial port path used to open the device
+    pub path: String,
+    /// USB serial number, if the OS reports one
+    pub serial_number: Option<String>,
+}
+
+impl PicoInfo {
+    /// Whether this device is the one picked out by `selector`, see `find_pico`
+    pub fn matches(&self, selector: &str) -> bool {
+        match Selector::parse(selector) {
+            Selector::Name(name) => self.name == name,
+            Selector::Serial(serial) => self
+                .serial_number
+                .as_ref()
+                .is_some_and(|x| x.eq_ignore_ascii_case(serial)),
+            Selector::Port(path) => self.path == path,
         }
+    }
+}
 
-        Ok(incoming)
+/// How a device is picked out. Usually by name, but devices can share a name,
+/// e.g. when freshly flashed, so they can also be picked by USB serial number
+/// with `id:<serial>` or by serial port with `port:<path>`.
+enum Selector<'a> {
+    Name(&'a str),
+    Serial(&'a str),
+    Port(&'a str),
+}
+
+impl<'a> Selector<'a> {
+    fn parse(selector: &'a str) -> Selector<'a> {
+        if let Some(serial) = selector.strip_prefix("id:") {
+            Selector::Serial(serial)
+        } else if let Some(path) = selector.strip_prefix("port:") {
+            Selector::Port(path)
+        } else {
+            Selector::Name(selector)
+        }
     }
 }
 
 /// Find all USB serial ports matching the PicoROM VID:PID
-fn enumerate_ports() -> Result<Vec<String>> {
+/// Returns the port path and USB serial number of each
+fn enumerate_ports() -> Result<Vec<(String, Option<String>)>> {
     let mut ports = Vec::new();
     let all_ports = serialport::available_ports()?;
 
     for p in all_ports.iter() {
-        match &p.port_type {
-            serialport::SerialPortType::UsbPort(info) => {
-                if info.vid == 0x2e8a && info.pid == 0x000a {
-                    ports.push(p.port_name.clone());
-                }
+        if let serialport::SerialPortType::UsbPort(info) = &p.port_type {
+            if info.vid == 0x2e8a && info.pid == 0x000a {
+                ports.push((p.port_name.clone(), info.serial_number.clone()));
             }
-            _ => {}
         }
     }
 
@@ -541,44 +1148,129 @@ fn read_cache_file() -> Result<HashMap<String, String>> {
     Ok(entries)
 }
 
-pub fn enumerate_picos() -> Result<HashMap<String, PicoLink>> {
+/// Find all connected PicoROMs
+/// Each device is only opened long enough to read its name.
+pub fn enumerate_picos() -> Result<Vec<PicoInfo>> {
+    enumerate_picos_with_baud(DEFAULT_BAUD)
+}
+
+pub fn enumerate_picos_with_baud(baud: u32) -> Result<Vec<PicoInfo>> {
+    Ok(scan_ports(baud)?.found)
+}
+
+/// Result of opening every PicoROM port
+struct Scan {
+    found: Vec<PicoInfo>,
+    /// Ports that couldn't be opened because of their permissions
+    denied: Vec<String>,
+    /// Ports locked by another process
+    busy: Vec<String>,
+}
+
+fn scan_ports(baud: u32) -> Result<Scan> {
     let mut cache_data = HashMap::new();
-    let mut found = HashMap::new();
-    for p in enumerate_ports()?.iter() {
-        let link = PicoLink::open(p, false);
-        if let Ok(mut link) = link {
-            if let Ok(ident) = link.get_parameter("name") {
-                cache_data.insert(ident.clone(), p.to_string());
-                found.insert(ident, link);
+    let mut found = Vec::new();
+    let mut denied = Vec::new();
+    let mut busy = Vec::new();
+    for (path, serial_number) in enumerate_ports()? {
+        match PicoLink::open_with_baud(&path, baud) {
+            Ok(mut link) => {
+                if let Ok(name) = link.get_parameter("name") {
+                    cache_data.insert(name.clone(), path.clone());
+                    found.push(PicoInfo {
+                        name,
+                        path,
+                        serial_number,
+                    });
+                }
             }
+            Err(e) => match e.downcast_ref() {
+                Some(PicoError::Permission(_)) => {
+                    debug!("No permission to open {}", path);
+                    denied.push(path);
+                }
+                Some(PicoError::Busy(_)) => {
+                    debug!("{} is locked by another process", path);
+                    busy.push(path);
+                }
+                _ => {}
+            },
         }
     }
 
-    write_cache_file(cache_data).unwrap(); // don't care if it fails
+    // The cache is only an optimization, so failing to write it isn't an error
+    if let Err(e) = write_cache_file(cache_data) {
+        debug!("Could not write device cache: {}", e);
+    }
 
-    Ok(found)
+    Ok(Scan {
+        found,
+        denied,
+        busy,
+    })
 }
 
 pub fn find_pico(name: &str) -> Result<PicoLink> {
+    find_pico_with_baud(name, DEFAULT_BAUD)
+}
+
+/// Open the device picked out by `name`, which is a device name, `id:<serial>`
+/// or `port:<path>`
+pub fn find_pico_with_baud(name: &str, baud: u32) -> Result<PicoLink> {
+    match Selector::parse(name) {
+        // Opened directly, so it needn't be a port enumeration would find
+        Selector::Port(path) => return PicoLink::open_with_baud(path, baud),
+        // Only names are cached
+        Selector::Serial(_) => return find_scanned_pico(name, baud),
+        Selector::Name(_) => {}
+    }
+
     // Check cache first
     let cached_paths = read_cache_file().unwrap_or_default();
     if let Some(path) = cached_paths.get(name) {
-        if let Ok(mut link) = PicoLink::open(path, false) {
-            if let Ok(ident) = link.get_parameter("name") {
-                if ident == name {
-                    println!("Found in cache");
-                    return Ok(link);
+        match PicoLink::open_with_baud(path, baud) {
+            Ok(mut link) => {
+                if let Ok(ident) = link.get_parameter("name") {
+                    if ident == name {
+                        debug!("Found '{}' in cache at {}", name, path);
+                        return Ok(link);
+                    }
                 }
             }
+            // Most likely the device we want, being used by someone else
+            Err(e) if matches!(e.downcast_ref(), Some(PicoError::Busy(_))) => {
+                return Err(PicoError::Busy(format!("PicoROM '{}' ({})", name, path)).into());
+            }
+            Err(_) => {}
         }
     }
 
     // If it wasn't found in the cache then do a full enumeration
-    let mut found = enumerate_picos()?;
+    find_scanned_pico(name, baud)
+}
 
-    if let Some(pico) = found.remove(name) {
-        Ok(pico)
+/// Open the device picked out by `name` after enumerating every port
+fn find_scanned_pico(name: &str, baud: u32) -> Result<PicoLink> {
+    let scan = scan_ports(baud)?;
+
+    // If it wasn't found it may be one of the ports that couldn't be opened
+    if let Some(info) = scan.found.iter().find(|x| x.matches(name)) {
+        PicoLink::open_with_baud(&info.path, baud)
+    } else if !scan.busy.is_empty() {
+        Err(PicoError::Busy(format!(
+            "PicoROM '{}' not found, {}",
+            name,
+            scan.busy.join(", ")
+        ))
+        .into())
+    } else if !scan.denied.is_empty() {
+        Err(PicoError::Permission(format!(
+            "PicoROM '{}' not found, and {} could not be opened",
+            name,
+            scan.denied.join(", ")
+        ))
+        .into())
     } else {
-        Err(anyhow!("PicoROM '{}' not found.", name))
+        Err(PicoError::NotFound(name.to_string()).into())
     }
 }
@@ -0,0 +1,125 @@
+//! Advisory locks that stop two processes talking to the same port at once.
+//!
+//! Each opened port holds an OS file lock on a file named after it in the
+//! cache directory. Links within one process share the lock, so taking it
+//! twice doesn't report the process's own port as busy. The port itself is
+//! still opened exclusively, so a link must be dropped before its port can
+//! be opened again, e.g. when reconnecting.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, Mutex, Weak};
+
+use dirs::cache_dir;
+use log::debug;
+
+use crate::{PicoError, Transport};
+
+/// Locks held by this process, by lock file path
+static HELD: LazyLock<Mutex<HashMap<PathBuf, Weak<File>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn lock_path(port_path: &str) -> Option<PathBuf> {
+    let name: String = port_path
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    cache_dir().map(|x| x.join("picorom_locks").join(format!("{}.lock", name)))
+}
+
+fn try_lock(path: &PathBuf) -> io::Result<Option<File>> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Take the lock for `port_path`, failing with `PicoError::Busy` if another
+/// process holds it. Locking is best effort, so other failures only skip it.
+pub(crate) fn lock_port(port_path: &str) -> Result<Option<Arc<File>>> {
+    let Some(path) = lock_path(port_path) else {
+        return Ok(None);
+    };
+
+    let mut held = HELD.lock().unwrap();
+    if let Some(file) = held.get(&path).and_then(Weak::upgrade) {
+        return Ok(Some(file));
+    }
+
+    match try_lock(&path) {
+        Ok(Some(file)) => {
+            let file = Arc::new(file);
+            held.insert(path, Arc::downgrade(&file));
+            Ok(Some(file))
+        }
+        Ok(None) => Err(PicoError::Busy(port_path.to_string()).into()),
+        Err(e) => {
+            debug!("Could not lock {}: {}", port_path, e);
+            Ok(None)
+        }
+    }
+}
+
+/// A transport that holds its port's lock until it is dropped
+pub(crate) struct LockedTransport<T> {
+    pub(crate) inner: T,
+    pub(crate) _lock: Option<Arc<File>>,
+}
+
+impl<T: Read> Read for LockedTransport<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<T: Write> Write for LockedTransport<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Transport> Transport for LockedTransport<T> {
+    fn bytes_to_read(&mut self) -> Result<u32> {
+        self.inner.bytes_to_read()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_is_shared_within_process_and_exclusive_outside() {
+        let port = format!("/dev/test-lock-{}", std::process::id());
+        let Some(first) = lock_port(&port).unwrap() else {
+            return; // No cache directory to lock in
+        };
+        let second = lock_port(&port).unwrap().unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        // A separate open of the lock file stands in for another process
+        let path = lock_path(&port).unwrap();
+        assert!(try_lock(&path).unwrap().is_none());
+
+        drop(first);
+        drop(second);
+        assert!(try_lock(&path).unwrap().is_some());
+        fs::remove_file(path).unwrap();
+    }
+}
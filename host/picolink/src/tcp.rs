@@ -0,0 +1,352 @@
+//! Access to PicoROMs attached to another machine, through `picorom serve`.
+//!
+//! Each TCP connection starts with one request line from the client:
+//! - `list` is answered with `ok`, then a `name<TAB>path` line per device, then the
+//!   connection is closed.
+//! - `open <name>` is answered with `ok`, after which the connection carries the
+//!   device's packets unchanged, starting with its greeting.
+//!
+//! Failures are answered with `error <message>` and the connection is closed.
+//! The server side is `serve_client`.
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread::sleep;
+use std::time::Duration;
+
+use log::{debug, warn};
+
+use crate::{
+    default_response_timeout, enumerate_picos_with_baud, PicoError, PicoInfo, PicoLink, Transport,
+    GREETING,
+};
+
+/// Port `picorom serve` listens on by default
+pub const DEFAULT_SERVE_PORT: u16 = 4242;
+
+/// How long to wait for the server to answer a request, which may need an enumeration
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for a device's response, allowing for network latency
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Shortest and longest sleeps while a bridged connection is idle
+const BRIDGE_MIN_WAIT: Duration = Duration::from_micros(10);
+const BRIDGE_MAX_WAIT: Duration = Duration::from_millis(1);
+
+/// How long a read waits before failing, matching the serial port timeout
+const READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A connection to a device through `picorom serve`
+pub struct TcpTransport {
+    stream: TcpStream,
+    /// Bytes already taken off the socket by `bytes_to_read`
+    received: VecDeque<u8>,
+}
+
+impl TcpTransport {
+    fn new(stream: TcpStream) -> Result<TcpTransport> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        Ok(TcpTransport {
+            stream,
+            received: VecDeque::new(),
+        })
+    }
+}
+
+impl Read for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.received.is_empty() {
+            return self.stream.read(buf);
+        }
+        self.received.read(buf)
+    }
+}
+
+impl Write for TcpTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Transport for TcpTransport {
+    fn bytes_to_read(&mut self) -> Result<u32> {
+        self.stream.set_nonblocking(true)?;
+        let mut buf = [0u8; 256];
+        let result: Result<()> = loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => break Err(PicoError::Disconnected.into()),
+                Ok(len) => self.received.extend(&buf[..len]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e.into()),
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        result?;
+
+        Ok(self.received.len() as u32)
+    }
+}
+
+/// Turn a server address into host:port, adding the default port if there isn't one.
+/// Accepts `host`, `host:port`, `[ipv6]`, `[ipv6]:port` and a bare IPv6 address.
+fn with_port(addr: &str) -> Result<String> {
+    let invalid = || anyhow!("Invalid server address '{}'", addr);
+    let check_port = |port: &str| port.parse::<u16>().map_err(|_| invalid());
+
+    if let Some(rest) = addr.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+        return match rest {
+            "" => Ok(format!("[{}]:{}", host, DEFAULT_SERVE_PORT)),
+            x => {
+                let port = x.strip_prefix(':').ok_or_else(invalid)?;
+                Ok(format!("[{}]:{}", host, check_port(port)?))
+            }
+        };
+    }
+
+    match addr.matches(':').count() {
+        0 if !addr.is_empty() => Ok(format!("{}:{}", addr, DEFAULT_SERVE_PORT)),
+        0 => Err(invalid()),
+        1 => {
+            let (host, port) = addr.split_once(':').unwrap();
+            if host.is_empty() {
+                return Err(invalid());
+            }
+            Ok(format!("{}:{}", host, check_port(port)?))
+        }
+        // More than one colon without brackets can only be an IPv6 address
+        _ => Ok(format!("[{}]:{}", addr, DEFAULT_SERVE_PORT)),
+    }
+}
+
+/// Parse a `list` reply line. The path is the last field, so names may contain tabs.
+fn parse_device_entry(line: &str) -> Option<(&str, &str)> {
+    line.rsplit_once('\t')
+}
+
+/// Send a request line and check the server accepted it
+fn request(addr: &str, line: &str) -> Result<BufReader<TcpStream>> {
+    let mut stream = TcpStream::connect(with_port(addr)?)
+        .with_context(|| format!("Could not connect to {}", addr))?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    writeln!(stream, "{}", line)?;
+
+    let mut reader = BufReader::new(stream);
+    let mut reply = String::new();
+    reader.read_line(&mut reply)?;
+    match reply.trim_end() {
+        "ok" => Ok(reader),
+        x => match x.strip_prefix("error ") {
+            Some(msg) => Err(anyhow!("{}: {}", addr, msg)),
+            None => Err(anyhow!("{}: unexpected reply '{}'", addr, x)),
+        },
+    }
+}
+
+/// List the PicoROMs attached to a remote `picorom serve`
+pub fn enumerate_remote(addr: &str) -> Result<Vec<PicoInfo>> {
+    let reader = request(addr, "list")?;
+    let mut found = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let (name, path) = parse_device_entry(&line)
+            .ok_or_else(|| anyhow!("{}: invalid device entry '{}'", addr, line))?;
+        found.push(PicoInfo {
+            name: name.to_string(),
+            path: format!("{}:{}", addr, path),
+            serial_number: None,
+        });
+    }
+    Ok(found)
+}
+
+/// Open a PicoROM attached to a remote `picorom serve`
+pub fn find_remote_pico(addr: &str, name: &str) -> Result<PicoLink> {
+    if name.contains('\n') {
+        return Err(anyhow!("Invalid device name '{}'", name));
+    }
+
+    let reader = request(addr, &format!("open {}", name))?;
+    // The reply was read a line at a time, so keep anything buffered after it
+    let buffered = reader.buffer().to_vec();
+    let mut transport = TcpTransport::new(reader.into_inner())?;
+    transport.received.extend(buffered);
+
+    let mut link = PicoLink::connect(transport, &format!("{}:{}", addr, name))?;
+    link.set_response_timeout(RESPONSE_TIMEOUT.max(default_response_timeout()));
+    Ok(link)
+}
+
+/// Write a reply line, logging it when it is an error
+fn reply(stream: &mut TcpStream, result: Result<()>) -> Result<()> {
+    match result {
+        Ok(()) => writeln!(stream, "ok")?,
+        Err(e) => {
+            warn!("{}: {}", stream.peer_addr()?, e);
+            writeln!(stream, "error {}", e.to_string().replace('\n', " "))?;
+        }
+    }
+    Ok(())
+}
+
+/// Answer one client of `picorom serve`, opening local devices at `baud`.
+/// Returns once the client disconnects.
+pub fn serve_client(mut stream: TcpStream, baud: u32) -> Result<()> {
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+
+    let mut line = String::new();
+    BufReader::new(stream.try_clone()?).read_line(&mut line)?;
+    let line = line.trim_end();
+    debug!("{}: request '{}'", stream.peer_addr()?, line);
+
+    if line == "list" {
+        let found = enumerate_picos_with_baud(baud);
+        let found = match found {
+            Ok(found) => found,
+            Err(e) => return reply(&mut stream, Err(e)),
+        };
+        reply(&mut stream, Ok(()))?;
+        for info in found {
+            writeln!(stream, "{}\t{}", info.name, info.path)?;
+        }
+        return Ok(());
+    }
+
+    let Some(name) = line.strip_prefix("open ") else {
+        return reply(&mut stream, Err(anyhow!("unknown request '{}'", line)));
+    };
+    let link = match open_enumerated(name, baud) {
+        Ok(link) => link,
+        Err(e) => return reply(&mut stream, Err(e)),
+    };
+    reply(&mut stream, Ok(()))?;
+    // The greeting was used up finding the device, so repeat it for the client
+    stream.write_all(GREETING)?;
+
+    bridge(stream, link.into_transport())
+}
+
+/// Open a device for a client, by name or `id:` selector. Only devices found by
+/// enumeration are opened, so clients can't have any other local path opened
+/// with a `port:` selector.
+fn open_enumerated(name: &str, baud: u32) -> Result<PicoLink> {
+    if name.starts_with("port:") {
+        return Err(anyhow!(
+            "port: selectors aren't accepted from remote clients"
+        ));
+    }
+    let found = enumerate_picos_with_baud(baud)?;
+    let info = found
+        .iter()
+        .find(|x| x.matches(name))
+        .ok_or_else(|| PicoError::NotFound(name.to_string()))?;
+    PicoLink::open_with_baud(&info.path, baud)
+}
+
+/// Pass bytes both ways between a client and a device until the client disconnects
+fn bridge(mut stream: TcpStream, mut device: Box<dyn Transport>) -> Result<()> {
+    stream.set_nonblocking(true)?;
+    let mut buf = [0u8; 256];
+    let mut idle = Duration::ZERO;
+
+    loop {
+        let mut moved = false;
+
+        match stream.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(len) => {
+                device.write_all(&buf[..len])?;
+                moved = true;
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let waiting = device.bytes_to_read()? as usize;
+        if waiting > 0 {
+            let len = device.read(&mut buf[..waiting.min(256)])?;
+            stream.set_nonblocking(false)?;
+            stream.write_all(&buf[..len])?;
+            stream.set_nonblocking(true)?;
+            moved = true;
+        }
+
+        // Back off while both sides are quiet
+        idle = if moved {
+            Duration::ZERO
+        } else {
+            (idle * 2).clamp(BRIDGE_MIN_WAIT, BRIDGE_MAX_WAIT)
+        };
+        if !idle.is_zero() {
+            sleep(idle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_port_adds_default_port() {
+        assert_eq!(with_port("bench").unwrap(), "bench:4242");
+        assert_eq!(with_port("10.0.0.2").unwrap(), "10.0.0.2:4242");
+        assert_eq!(with_port("[::1]").unwrap(), "[::1]:4242");
+        assert_eq!(with_port("fe80::1").unwrap(), "[fe80::1]:4242");
+        assert_eq!(with_port("::1").unwrap(), "[::1]:4242");
+    }
+
+    #[test]
+    fn with_port_keeps_given_port() {
+        assert_eq!(with_port("bench:5000").unwrap(), "bench:5000");
+        assert_eq!(with_port("[::1]:5000").unwrap(), "[::1]:5000");
+    }
+
+    #[test]
+    fn with_port_rejects_malformed() {
+        for addr in [
+            "",
+            ":5000",
+            "bench:",
+            "bench:http",
+            "bench:70000",
+            "[::1",
+            "[::1]5000",
+        ] {
+            assert!(with_port(addr).is_err(), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn serve_refuses_port_selectors() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_client(stream, crate::DEFAULT_BAUD).unwrap();
+        });
+
+        let err = find_remote_pico(&addr, "port:/dev/null").err().unwrap();
+        assert!(err.to_string().contains("port: selectors"), "{}", err);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn device_entry_path_is_last_field() {
+        assert_eq!(
+            parse_device_entry("cpu\t/dev/ttyACM0"),
+            Some(("cpu", "/dev/ttyACM0"))
+        );
+        assert_eq!(parse_device_entry("a\tb\tCOM3"), Some(("a\tb", "COM3")));
+        assert_eq!(parse_device_entry("no path"), None);
+    }
+}
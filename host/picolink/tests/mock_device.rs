@@ -0,0 +1,616 @@
+//! Protocol tests against an in-memory stand-in for the PicoROM firmware
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use picolink::{PicoError, PicoInfo, PicoLink, ResetLevel, Transport};
+
+const POINTER_SET: u8 = 3;
+const POINTER_GET: u8 = 4;
+const POINTER_CUR: u8 = 5;
+const WRITE: u8 = 6;
+const READ: u8 = 7;
+const READ_DATA: u8 = 8;
+const COMMIT_FLASH: u8 = 12;
+const COMMIT_DONE: u8 = 13;
+const COMMIT_RANGE: u8 = 14;
+const CHECKSUM: u8 = 15;
+const CHECKSUM_VALUE: u8 = 16;
+const PARAMETER_SET: u8 = 20;
+const PARAMETER_GET: u8 = 21;
+const PARAMETER: u8 = 22;
+const PARAMETER_ERROR: u8 = 23;
+const PARAMETER_QUERY: u8 = 24;
+const COMMS_START: u8 = 80;
+const COMMS_END: u8 = 81;
+const COMMS_DATA: u8 = 82;
+
+const ROM_SIZE: usize = 256 * 1024;
+const TX_PAYLOAD: usize = 30;
+
+/// Device state, shared between the transport and the test
+struct State {
+    rom: Vec<u8>,
+    pointer: usize,
+    parameters: Vec<(String, String)>,
+    committed: bool,
+    comms_active: bool,
+    /// Writes that time out before the next one is accepted
+    write_timeouts: u32,
+    /// Number of successful writes to the transport
+    write_calls: usize,
+    /// Every request received, as (kind, payload)
+    requests: Vec<(u8, Vec<u8>)>,
+    rx: Vec<u8>,
+    tx: VecDeque<u8>,
+}
+
+impl State {
+    fn respond(&mut self, kind: u8, payload: &[u8]) {
+        assert!(payload.len() <= TX_PAYLOAD);
+        self.tx.push_back(kind);
+        self.tx.push_back(payload.len() as u8);
+        self.tx.extend(payload);
+    }
+
+    fn parameter(&self, name: &str) -> Option<String> {
+        self.parameters
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, value)| value.clone())
+    }
+
+    fn handle(&mut self, kind: u8, payload: &[u8]) {
+        self.requests.push((kind, payload.to_vec()));
+        let text = || {
+            String::from_utf8(payload.to_vec())
+                .unwrap()
+                .trim_end_matches('\0')
+                .to_string()
+        };
+
+        match kind {
+            POINTER_SET => self.pointer = u32::from_le_bytes(payload.try_into().unwrap()) as usize,
+            POINTER_GET => self.respond(POINTER_CUR, &(self.pointer as u32).to_le_bytes()),
+            WRITE => {
+                self.rom[self.pointer..self.pointer + payload.len()].copy_from_slice(payload);
+                self.pointer += payload.len();
+            }
+            READ => {
+                let end = (self.pointer + TX_PAYLOAD).min(ROM_SIZE);
+                let data = self.rom[self.pointer..end].to_vec();
+                self.pointer = end;
+                self.respond(READ_DATA, &data);
+            }
+            COMMIT_FLASH | COMMIT_RANGE => {
+                self.committed = true;
+                self.respond(COMMIT_DONE, &[]);
+            }
+            CHECKSUM => {
+                let addr = u32::from_le_bytes(payload[0..4].try_into().unwrap()) as usize;
+                let len = u32::from_le_bytes(payload[4..8].try_into().unwrap()) as usize;
+                let crc = crc32(&self.rom[addr..addr + len]);
+                self.respond(CHECKSUM_VALUE, &crc.to_le_bytes());
+            }
+            PARAMETER_GET => match self.parameter(&text()) {
+                Some(value) => self.respond(PARAMETER, value.as_bytes()),
+                None => self.respond(PARAMETER_ERROR, &[]),
+            },
+            PARAMETER_SET => {
+                let text = text();
+                let (name, value) = text.split_once(',').unwrap();
+                match self.parameters.iter_mut().find(|(x, _)| x == name) {
+                    Some(entry) => {
+                        entry.1 = value.to_string();
+                        self.respond(PARAMETER, value.as_bytes());
+                    }
+                    None => self.respond(PARAMETER_ERROR, &[]),
+                }
+            }
+            PARAMETER_QUERY => {
+                let next = if payload.is_empty() {
+                    self.parameters.first()
+                } else {
+                    let prev = text();
+                    let idx = self.parameters.iter().position(|(x, _)| *x == prev);
+                    idx.and_then(|x| self.parameters.get(x + 1))
+                };
+                let name = next.map(|(x, _)| x.clone()).unwrap_or_default();
+                self.respond(PARAMETER, name.as_bytes());
+            }
+            COMMS_START => self.comms_active = true,
+            COMMS_END => self.comms_active = false,
+            // The target on the other end of comms echoes everything back
+            COMMS_DATA => self.respond(COMMS_DATA, payload),
+            _ => panic!("unexpected request kind {}", kind),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct MockDevice(Arc<Mutex<State>>);
+
+impl MockDevice {
+    fn new() -> MockDevice {
+        let parameters = [
+            ("name", "mock"),
+            ("addr_mask", "0x3ffff"),
+            ("max_payload", "62"),
+            ("reset", "z"),
+        ];
+        MockDevice(Arc::new(Mutex::new(State {
+            rom: vec![0; ROM_SIZE],
+            pointer: 0,
+            parameters: parameters
+                .iter()
+                .map(|(x, y)| (x.to_string(), y.to_string()))
+                .collect(),
+            committed: false,
+            comms_active: false,
+            write_timeouts: 0,
+            write_calls: 0,
+            requests: Vec::new(),
+            rx: Vec::new(),
+            tx: VecDeque::new(),
+        })))
+    }
+
+    fn link(&self) -> PicoLink {
+        PicoLink::from_transport(self.clone(), "mock")
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.0.lock().unwrap()
+    }
+
+    /// Payloads of every request of `kind` received so far
+    fn requests(&self, kind: u8) -> Vec<Vec<u8>> {
+        self.state()
+            .requests
+            .iter()
+            .filter(|(x, _)| *x == kind)
+            .map(|(_, payload)| payload.clone())
+            .collect()
+    }
+}
+
+impl Read for MockDevice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        let len = buf.len().min(state.tx.len());
+        for x in buf.iter_mut().take(len) {
+            *x = state.tx.pop_front().unwrap();
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MockDevice {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        if state.write_timeouts > 0 {
+            state.write_timeouts -= 1;
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        state.write_calls += 1;
+        state.rx.extend_from_slice(buf);
+        while state.rx.len() >= 2 && state.rx.len() >= 2 + state.rx[1] as usize {
+            let size = state.rx[1] as usize;
+            let packet: Vec<u8> = state.rx.drain(..2 + size).collect();
+            state.handle(packet[0], &packet[2..]);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MockDevice {
+    fn bytes_to_read(&mut self) -> anyhow::Result<u32> {
+        Ok(self.state().tx.len() as u32)
+    }
+}
+
+/// Bitwise CRC32, as the firmware computes it
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for x in data {
+        crc ^= *x as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|x| (x * 7 + x / 251) as u8).collect()
+}
+
+#[test]
+fn connect_skips_to_the_greeting() {
+    let device = MockDevice::new();
+    device.state().tx.extend(b"\0\0noise PicoROM Hello");
+
+    let mut link = PicoLink::connect(device.clone(), "mock").unwrap();
+    assert_eq!(link.get_ident().unwrap(), "mock");
+}
+
+#[test]
+fn connect_without_greeting_fails() {
+    let device = MockDevice::new();
+    device.state().tx.extend(b"PicoROM");
+
+    assert!(PicoLink::connect(device.clone(), "mock").is_err());
+}
+
+#[test]
+fn get_parameter() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+
+    assert_eq!(link.get_parameter("name").unwrap(), "mock");
+    assert_eq!(link.get_parameter_u32("addr_mask").unwrap(), 0x3ffff);
+    let err = link.get_parameter("missing").unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<PicoError>(),
+        Some(&PicoError::ParameterRejected("missing".to_string()))
+    );
+}
+
+#[test]
+fn get_parameters_lists_all() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+
+    assert_eq!(
+        link.get_parameters().unwrap(),
+        ["name", "addr_mask", "max_payload", "reset"]
+    );
+}
+
+#[test]
+fn set_parameter() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+
+    assert!(link.set_ident("renamed").unwrap());
+    assert_eq!(link.get_ident().unwrap(), "renamed");
+    assert!(link.set_parameter("missing", "1").is_err());
+}
+
+#[test]
+fn set_ident_to_current_name_writes_nothing() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+
+    assert!(!link.set_ident("mock").unwrap());
+    assert!(device.requests(PARAMETER_SET).is_empty());
+}
+
+#[test]
+fn reset_level_is_validated_before_sending() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+
+    assert!(link.set_parameter("reset", "sideways").is_err());
+    assert!(device.requests(PARAMETER_SET).is_empty());
+
+    link.reset(ResetLevel::Low).unwrap();
+    assert_eq!(device.state().parameter("reset").unwrap(), "low");
+}
+
+#[test]
+fn upload_uses_negotiated_chunk_size() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+    let data = pattern(1000);
+
+    link.upload(&data, |_| {}).unwrap();
+
+    assert_eq!(device.state().rom[..data.len()], data[..]);
+    let writes = device.requests(WRITE);
+    assert_eq!(writes.len(), 1000_usize.div_ceil(62));
+    assert!(writes.iter().all(|x| x.len() <= 62));
+}
+
+#[test]
+fn upload_batches_write_packets() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+    let data = pattern(4000);
+    link.set_chunk_size(62).unwrap();
+
+    link.upload(&data, |_| {}).unwrap();
+
+    assert_eq!(device.state().rom[..data.len()], data[..]);
+    assert_eq!(device.requests(WRITE).len(), 4000_usize.div_ceil(62));
+    // Pointer set and get, plus 65 Write packets in batches of 16
+    assert_eq!(device.state().write_calls, 2 + 5);
+}
+
+#[test]
+fn upload_falls_back_to_default_chunk_size() {
+    let device = MockDevice::new();
+    device
+        .state()
+        .parameters
+        .retain(|(name, _)| name != "max_payload");
+    let mut link = device.link();
+
+    link.upload(&pattern(100), |_| {}).unwrap();
+
+    let sizes: Vec<usize> = device.requests(WRITE).iter().map(|x| x.len()).collect();
+    assert_eq!(sizes, [30, 30, 30, 10]);
+}
+
+#[test]
+fn upload_to_then_download_from() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+    let data = pattern(333);
+
+    link.upload_to(0x1f000, &data, |_| {}).unwrap();
+    assert_eq!(
+        link.download_from(0x1f000, data.len(), |_| {}).unwrap(),
+        data
+    );
+}
+
+#[test]
+fn download_full_is_sized_by_addr_mask() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+    let data = pattern(4096);
+
+    link.upload(&data, |_| {}).unwrap();
+    link.set_addr_mask(0xfff).unwrap();
+
+    let progress = Cell::new(0);
+    let downloaded = link
+        .download_full(|x| progress.set(progress.get() + x))
+        .unwrap();
+    assert_eq!(progress.get(), 4096);
+    assert_eq!(downloaded, data);
+}
+
+#[test]
+fn read_range_checks_bounds_and_pointer() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+    let data = pattern(4096);
+
+    link.upload_to(0x1f000, &data, |_| {}).unwrap();
+    assert_eq!(
+        link.read_range(0x1f010, 100, |_| {}).unwrap(),
+        &data[0x10..0x10 + 100]
+    );
+
+    // Out of range reads are refused before anything is sent
+    let reads = device.requests(READ).len();
+    assert!(link.read_range((ROM_SIZE - 10) as u32, 20, |_| {}).is_err());
+    assert_eq!(device.requests(READ).len(), reads);
+}
+
+#[test]
+fn download_past_the_end_fails() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+
+    assert!(link
+        .download_from((ROM_SIZE - 10) as u32, 20, |_| {})
+        .is_err());
+}
+
+#[test]
+fn commit_rom() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+
+    link.commit_rom().unwrap();
+    assert!(device.state().committed);
+}
+
+#[test]
+fn commit_rom_verified_checks_dirty_flag() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+
+    // Firmware without the parameter is trusted
+    assert_eq!(link.rom_dirty(), None);
+    link.commit_rom_verified().unwrap();
+
+    // The mock never clears it, like a commit that didn't take
+    device
+        .state()
+        .parameters
+        .push(("rom_dirty".to_string(), "1".to_string()));
+    assert_eq!(link.rom_dirty(), Some(true));
+    let err = link.commit_rom_verified().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(PicoError::VerifyMismatch(_))
+    ));
+}
+
+#[test]
+fn commit_range_needs_protocol_version_2() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+
+    // Older firmware gets a full commit
+    link.commit_range(0x1000, 0x20).unwrap();
+    assert_eq!(device.requests(COMMIT_FLASH).len(), 1);
+    assert!(device.requests(COMMIT_RANGE).is_empty());
+
+    device
+        .state()
+        .parameters
+        .push(("protocol_version".to_string(), "2".to_string()));
+    link.commit_range(0x1000, 0x20).unwrap();
+    let expected = [0x1000u32.to_le_bytes(), 0x20u32.to_le_bytes()].concat();
+    assert_eq!(device.requests(COMMIT_RANGE), vec![expected]);
+}
+
+#[test]
+fn checksum_needs_protocol_version_3() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+    device.state().rom[0x100..0x109].copy_from_slice(b"123456789");
+
+    assert!(link.checksum(0x100, 9).is_err());
+    assert!(device.requests(CHECKSUM).is_empty());
+
+    device
+        .state()
+        .parameters
+        .push(("protocol_version".to_string(), "3".to_string()));
+    assert_eq!(link.checksum(0x100, 9).unwrap(), 0xcbf43926);
+    assert!(link.checksum(ROM_SIZE as u32 - 4, 8).is_err());
+}
+
+#[test]
+fn send_retries_timed_out_writes() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+
+    device.state().write_timeouts = 2;
+    assert!(link.commit_rom().is_err());
+    assert!(device.requests(COMMIT_FLASH).is_empty());
+
+    link.set_send_retries(2);
+    device.state().write_timeouts = 2;
+    link.commit_rom().unwrap();
+    assert_eq!(device.requests(COMMIT_FLASH).len(), 1);
+}
+
+#[test]
+fn comms_data_is_split_into_packets() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+    let data = pattern(70);
+
+    link.start_comms(0x1fe00).unwrap();
+    assert!(device.state().comms_active);
+    assert_eq!(
+        device.requests(COMMS_START),
+        [0x1fe00_u32.to_le_bytes().to_vec()]
+    );
+
+    // Every echoed packet is collected by the same poll
+    assert_eq!(link.poll_comms(Some(data.clone())).unwrap(), data);
+
+    let sizes: Vec<usize> = device
+        .requests(COMMS_DATA)
+        .iter()
+        .map(|x| x.len())
+        .collect();
+    assert_eq!(sizes, [30, 30, 10]);
+
+    link.end_comms().unwrap();
+    assert!(!device.state().comms_active);
+}
+
+#[test]
+fn comms_data_survives_parameter_requests() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+
+    link.start_comms(0x1fe00).unwrap();
+    device.state().respond(COMMS_DATA, b"hello");
+
+    assert_eq!(link.get_parameter("name").unwrap(), "mock");
+    assert_eq!(link.poll_comms(None).unwrap(), b"hello");
+}
+
+#[test]
+fn comms_flush_discards_pending_data() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+
+    link.start_comms(0x1fe00).unwrap();
+    device.state().respond(COMMS_DATA, b"stale");
+    link.comms_flush().unwrap();
+
+    assert!(link.poll_comms(None).unwrap().is_empty());
+    assert!(link.comms_active());
+}
+
+#[test]
+fn poll_comms_requires_active_comms() {
+    let device = MockDevice::new();
+    let mut link = device.link();
+
+    assert!(link.poll_comms(None).is_err());
+}
+
+/// Serve `device` to one client the way `picorom serve` does
+fn serve_once(device: MockDevice) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut line = Vec::new();
+        let mut byte = [0u8];
+        while byte[0] != b'\n' {
+            stream.read_exact(&mut byte).unwrap();
+            line.push(byte[0]);
+        }
+        assert_eq!(line, b"open mock\n");
+        stream.write_all(b"ok\nPicoROM Hello").unwrap();
+
+        let mut device = device;
+        stream.set_nonblocking(true).unwrap();
+        let mut buf = [0u8; 256];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => return,
+                Ok(len) => device.write_all(&buf[..len]).unwrap(),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(_) => return,
+            }
+            let len = device.read(&mut buf).unwrap();
+            stream.write_all(&buf[..len]).unwrap();
+            std::thread::sleep(std::time::Duration::from_micros(100));
+        }
+    });
+
+    addr
+}
+
+#[test]
+fn remote_device_over_tcp() {
+    let device = MockDevice::new();
+    let addr = serve_once(device.clone());
+    let mut link = picolink::find_remote_pico(&addr, "mock").unwrap();
+    let data = pattern(500);
+
+    assert_eq!(link.get_ident().unwrap(), "mock");
+    link.upload_to(0x100, &data, |_| {}).unwrap();
+    assert_eq!(link.download_from(0x100, data.len(), |_| {}).unwrap(), data);
+}
+
+#[test]
+fn pico_info_matches_selectors() {
+    let info = PicoInfo {
+        name: "pico".to_string(),
+        path: "/dev/ttyACM0".to_string(),
+        serial_number: Some("E6614103E7452D2F".to_string()),
+    };
+    assert!(info.matches("pico"));
+    assert!(info.matches("id:e6614103e7452d2f"));
+    assert!(info.matches("port:/dev/ttyACM0"));
+    assert!(!info.matches("id:pico"));
+    assert!(!info.matches("/dev/ttyACM0"));
+
+    let info = PicoInfo {
+        serial_number: None,
+        ..info
+    };
+    assert!(!info.matches("id:E6614103E7452D2F"));
+}
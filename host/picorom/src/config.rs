@@ -0,0 +1,59 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use crate::rom_size::RomSize;
+use crate::ui::ColorChoice;
+
+/// Defaults for options that weren't given on the command line
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Defaults {
+    /// ROM size for upload, upload-pattern and provision, e.g. "1MBit"
+    pub size: Option<RomSize>,
+    pub color: Option<ColorChoice>,
+    /// Device to use when no name is given and $PICOROM_DEVICE isn't set
+    pub device: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub defaults: Defaults,
+    /// Region name to range, e.g. `bios = "0x0..0x20000"`
+    #[serde(default)]
+    pub regions: BTreeMap<String, String>,
+    /// Where the config was loaded from, for error messages
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+/// $PICOROM_CONFIG, or picorom/config.toml in the user's config directory
+fn config_path() -> Option<PathBuf> {
+    match std::env::var_os("PICOROM_CONFIG") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => dirs::config_dir().map(|x| x.join("picorom").join("config.toml")),
+    }
+}
+
+impl Config {
+    /// Load the config file. A missing file gives an empty config.
+    pub fn load() -> Result<Config> {
+        let Some(path) = config_path() else {
+            return Ok(Config::default());
+        };
+
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("Could not read {:?}", path)),
+        };
+        let mut config: Config =
+            toml::from_str(&text).with_context(|| format!("Could not parse {:?}", path))?;
+        config.path = Some(path);
+        Ok(config)
+    }
+}
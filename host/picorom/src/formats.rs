@@ -0,0 +1,385 @@
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+/// Bytes of data in each HEX or SREC record
+const RECORD_LEN: usize = 16;
+
+/// Highest address accepted when decoding, far beyond any ROM size but small
+/// enough that a stray address doesn't allocate gigabytes
+const MAX_DECODED_LEN: u64 = 16 * 1024 * 1024;
+
+/// Read an image file as the bytes it places at each address from 0.
+/// Intel HEX and S-record files are decoded, anything else is read as raw binary.
+pub fn read_image(path: &Path) -> Result<Vec<u8>> {
+    let decode = match ImageFormat::from_path(path) {
+        ImageFormat::Bin => return Ok(fs::read(path)?),
+        ImageFormat::Hex => decode_ihex,
+        ImageFormat::Srec => decode_srec,
+    };
+    let text = fs::read_to_string(path)?;
+    decode(&text).with_context(|| format!("Could not decode {:?}", path))
+}
+
+/// File formats a ROM image can be written in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ImageFormat {
+    /// Raw binary
+    Bin,
+    /// Intel HEX
+    Hex,
+    /// Motorola S-record
+    Srec,
+}
+
+impl ImageFormat {
+    /// Pick a format from a file's extension, defaulting to raw binary
+    pub fn from_path(path: &Path) -> ImageFormat {
+        let ext = path
+            .extension()
+            .map(|x| x.to_string_lossy().to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("hex" | "ihx" | "ihex") => ImageFormat::Hex,
+            Some("srec" | "s19" | "s28" | "s37" | "mot") => ImageFormat::Srec,
+            _ => ImageFormat::Bin,
+        }
+    }
+
+    /// Check that addresses up to `end` fit in the records written to `path`.
+    /// .s19 and .s28 files are limited to 16 and 24-bit addresses.
+    pub fn check_fits(&self, path: &Path, end: u64) -> Result<()> {
+        let ext = path
+            .extension()
+            .map(|x| x.to_string_lossy().to_ascii_lowercase());
+        let bits = match (self, ext.as_deref()) {
+            (ImageFormat::Bin, _) => return Ok(()),
+            (ImageFormat::Srec, Some("s19")) => 16,
+            (ImageFormat::Srec, Some("s28")) => 24,
+            _ => 32,
+        };
+        if end > 1 << bits {
+            return Err(anyhow!(
+                "Addresses up to 0x{:x} don't fit in the {}-bit addresses of {:?}",
+                end - 1,
+                bits,
+                path
+            ));
+        }
+        Ok(())
+    }
+
+    /// Encode `data`, which starts at ROM address `addr`
+    pub fn encode(&self, data: &[u8], addr: u32) -> Vec<u8> {
+        let end = addr as u64 + data.len() as u64;
+        match self {
+            ImageFormat::Bin => data.to_vec(),
+            ImageFormat::Hex => encode_ihex(data, addr).into_bytes(),
+            ImageFormat::Srec => encode_srec(data, addr, end).into_bytes(),
+        }
+    }
+}
+
+/// One Intel HEX record. The checksum is the two's complement of the byte sum.
+fn ihex_record(out: &mut String, kind: u8, addr: u16, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(addr.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend(data);
+    let sum = bytes.iter().fold(0u8, |acc, x| acc.wrapping_add(*x));
+    bytes.push(sum.wrapping_neg());
+
+    out.push(':');
+    for b in bytes {
+        write!(out, "{:02X}", b).unwrap();
+    }
+    out.push('\n');
+}
+
+/// Intel HEX, using extended linear address records for addresses above 64KiB
+fn encode_ihex(data: &[u8], addr: u32) -> String {
+    let mut out = String::new();
+    let mut upper = None;
+    for (idx, chunk) in data.chunks(RECORD_LEN).enumerate() {
+        let chunk_addr = addr + (idx * RECORD_LEN) as u32;
+        // Records can't cross a 64KiB boundary, so split them where they would
+        let split = (0x10000 - (chunk_addr & 0xffff) as usize).min(chunk.len());
+        for (part, part_addr) in [
+            (&chunk[..split], chunk_addr),
+            (&chunk[split..], chunk_addr + split as u32),
+        ] {
+            if part.is_empty() {
+                continue;
+            }
+            let part_upper = (part_addr >> 16) as u16;
+            if upper != Some(part_upper) {
+                ihex_record(&mut out, 0x04, 0, &part_upper.to_be_bytes());
+                upper = Some(part_upper);
+            }
+            ihex_record(&mut out, 0x00, part_addr as u16, part);
+        }
+    }
+    ihex_record(&mut out, 0x01, 0, &[]);
+    out
+}
+
+/// One S-record. The count covers the address, data and checksum, and the
+/// checksum is the ones' complement of the byte sum.
+fn srec_record(out: &mut String, kind: u8, addr_len: usize, addr: u32, data: &[u8]) {
+    let mut bytes = vec![(addr_len + data.len() + 1) as u8];
+    bytes.extend(&addr.to_be_bytes()[4 - addr_len..]);
+    bytes.extend(data);
+    let sum = bytes.iter().fold(0u8, |acc, x| acc.wrapping_add(*x));
+    bytes.push(!sum);
+
+    write!(out, "S{}", kind).unwrap();
+    for b in bytes {
+        write!(out, "{:02X}", b).unwrap();
+    }
+    out.push('\n');
+}
+
+/// S-records, using the narrowest address width that reaches `end`
+fn encode_srec(data: &[u8], addr: u32, end: u64) -> String {
+    let (data_kind, end_kind, addr_len) = match end {
+        0..=0x1_0000 => (1, 9, 2),
+        0x1_0001..=0x100_0000 => (2, 8, 3),
+        _ => (3, 7, 4),
+    };
+
+    let mut out = String::new();
+    srec_record(&mut out, 0, 2, 0, b"picorom");
+    for (idx, chunk) in data.chunks(RECORD_LEN).enumerate() {
+        srec_record(
+            &mut out,
+            data_kind,
+            addr_len,
+            addr + (idx * RECORD_LEN) as u32,
+            chunk,
+        );
+    }
+    srec_record(&mut out, end_kind, addr_len, 0, &[]);
+    out
+}
+
+/// Decode the data records of an S-record file into a flat image, with any
+/// gaps between records zero filled. Header, count and start records are ignored.
+fn decode_srec(text: &str) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let lineno = idx + 1;
+
+        let kind = line
+            .strip_prefix('S')
+            .and_then(|x| x.chars().next())
+            .and_then(|x| x.to_digit(10))
+            .ok_or_else(|| anyhow!("Line {} isn't an S-record", lineno))?;
+        let bytes = record_bytes(&line[2..], lineno)?;
+
+        if bytes.len() < 2 || bytes[0] as usize != bytes.len() - 1 {
+            return Err(anyhow!("Line {} has the wrong byte count", lineno));
+        }
+        let sum = bytes.iter().fold(0u8, |acc, x| acc.wrapping_add(*x));
+        if sum != 0xff {
+            return Err(anyhow!("Line {} has a bad checksum", lineno));
+        }
+
+        let addr_len = match kind {
+            1 => 2,
+            2 => 3,
+            3 => 4,
+            0 | 5..=9 => continue,
+            _ => return Err(anyhow!("Line {} has unknown record type S{}", lineno, kind)),
+        };
+        let record = &bytes[1..bytes.len() - 1];
+        if record.len() < addr_len {
+            return Err(anyhow!("Line {} is too short for its address", lineno));
+        }
+        let (addr, payload) = record.split_at(addr_len);
+        let addr = addr.iter().fold(0u64, |acc, x| (acc << 8) | *x as u64);
+        place(&mut data, addr, payload, lineno)?;
+    }
+    Ok(data)
+}
+
+/// Decode the data records of an Intel HEX file into a flat image, with any
+/// gaps between records zero filled. Start address records are ignored.
+fn decode_ihex(text: &str) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    // Added to each record's address by extended segment and linear address records
+    let mut base = 0u64;
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let lineno = idx + 1;
+
+        let hex = line
+            .strip_prefix(':')
+            .ok_or_else(|| anyhow!("Line {} isn't an Intel HEX record", lineno))?;
+        let bytes = record_bytes(hex, lineno)?;
+        if bytes.len() < 5 || bytes[0] as usize != bytes.len() - 5 {
+            return Err(anyhow!("Line {} has the wrong byte count", lineno));
+        }
+        let sum = bytes.iter().fold(0u8, |acc, x| acc.wrapping_add(*x));
+        if sum != 0 {
+            return Err(anyhow!("Line {} has a bad checksum", lineno));
+        }
+
+        let addr = u16::from_be_bytes([bytes[1], bytes[2]]) as u64;
+        let payload = &bytes[4..bytes.len() - 1];
+        let upper = || -> Result<u64> {
+            match payload {
+                [hi, lo] => Ok(u16::from_be_bytes([*hi, *lo]) as u64),
+                _ => Err(anyhow!("Line {} has a malformed address record", lineno)),
+            }
+        };
+        match bytes[3] {
+            0x00 => place(&mut data, base + addr, payload, lineno)?,
+            0x01 => break,
+            0x02 => base = upper()? << 4,
+            0x04 => base = upper()? << 16,
+            0x03 | 0x05 => {}
+            kind => {
+                return Err(anyhow!(
+                    "Line {} has unknown record type {:02X}",
+                    lineno,
+                    kind
+                ))
+            }
+        }
+    }
+    Ok(data)
+}
+
+/// The bytes of a record given as hex digits
+fn record_bytes(hex: &str, lineno: usize) -> Result<Vec<u8>> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return Err(anyhow!(
+            "Line {} isn't an even number of hex digits",
+            lineno
+        ));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|x| u8::from_str_radix(&hex[x..x + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| anyhow!("Line {} has invalid hex digits", lineno))
+}
+
+/// Copy a record's payload into `data` at `addr`, growing it as needed
+fn place(data: &mut Vec<u8>, addr: u64, payload: &[u8], lineno: usize) -> Result<()> {
+    let end = addr + payload.len() as u64;
+    if end > MAX_DECODED_LEN {
+        return Err(anyhow!(
+            "Line {} writes to 0x{:x}, beyond any ROM size",
+            lineno,
+            end - 1
+        ));
+    }
+
+    let (addr, end) = (addr as usize, end as usize);
+    if data.len() < end {
+        data.resize(end, 0);
+    }
+    data[addr..end].copy_from_slice(payload);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ihex_records() {
+        let hex = encode_ihex(&[0x01, 0x02, 0x03], 0x10);
+        assert_eq!(hex, ":020000040000FA\n:03001000010203E7\n:00000001FF\n");
+    }
+
+    #[test]
+    fn ihex_splits_at_64k() {
+        let hex = encode_ihex(&[0xaa; 4], 0xfffe);
+        assert_eq!(
+            hex,
+            ":020000040000FA\n:02FFFE00AAAAAD\n:020000040001F9\n:02000000AAAAAA\n:00000001FF\n"
+        );
+    }
+
+    #[test]
+    fn srec_address_width_follows_end() {
+        let srec = encode_srec(&[0x01, 0x02, 0x03], 0x10, 0x13);
+        assert_eq!(
+            srec,
+            "S00A00007069636F726F6DFC\nS1060010010203E3\nS9030000FC\n"
+        );
+        let srec = encode_srec(&[0x01], 0x20000, 0x20001);
+        assert!(srec.contains("\nS20502000001F7\n"));
+        assert!(srec.ends_with("S804000000FB\n"));
+    }
+
+    #[test]
+    fn srec_round_trips() {
+        let data: Vec<u8> = (0..100).collect();
+        let srec = ImageFormat::Srec.encode(&data, 0x1ff80);
+        let decoded = decode_srec(std::str::from_utf8(&srec).unwrap()).unwrap();
+        assert_eq!(&decoded[0x1ff80..], &data[..]);
+        assert!(decoded[..0x1ff80].iter().all(|x| *x == 0));
+    }
+
+    #[test]
+    fn ihex_round_trips() {
+        // Crosses a 64KiB boundary, so it needs an extended linear address record
+        let data: Vec<u8> = (0..100).collect();
+        let hex = ImageFormat::Hex.encode(&data, 0xffd0);
+        let decoded = decode_ihex(std::str::from_utf8(&hex).unwrap()).unwrap();
+        assert_eq!(&decoded[0xffd0..], &data[..]);
+        assert!(decoded[..0xffd0].iter().all(|x| *x == 0));
+    }
+
+    #[test]
+    fn ihex_segment_address_and_errors() {
+        // Extended segment address 0x1000 puts the data at 0x10000 + 0x10
+        let decoded = decode_ihex(":020000021000EC\n:03001000010203E7\n:00000001FF\n").unwrap();
+        assert_eq!(&decoded[0x10010..], &[1, 2, 3]);
+
+        assert!(decode_ihex(":03001000010203E8\n").is_err());
+        assert!(decode_ihex(":04001000010203E7\n").is_err());
+        assert!(decode_ihex("03001000010203E7\n").is_err());
+    }
+
+    #[test]
+    fn read_image_decodes_written_files() {
+        let data: Vec<u8> = (0..=255).cycle().take(70000).collect();
+        let dir = std::env::temp_dir();
+        for (name, format) in [("hex", ImageFormat::Hex), ("srec", ImageFormat::Srec)] {
+            let path = dir.join(format!("picorom-{}-roundtrip.{}", std::process::id(), name));
+            assert_eq!(ImageFormat::from_path(&path), format);
+            fs::write(&path, format.encode(&data, 0)).unwrap();
+            let read = read_image(&path);
+            fs::remove_file(&path).unwrap();
+            assert_eq!(read.unwrap(), data, "{}", name);
+        }
+    }
+
+    #[test]
+    fn srec_rejects_bad_checksum() {
+        assert!(decode_srec("S1060010010203E3\n").is_ok());
+        assert!(decode_srec("S1060010010203E4\n").is_err());
+        assert!(decode_srec("S1050010010203E3\n").is_err());
+    }
+
+    #[test]
+    fn srec_extension_limits_width() {
+        let format = ImageFormat::from_path(Path::new("rom.s19"));
+        assert_eq!(format, ImageFormat::Srec);
+        assert!(format.check_fits(Path::new("rom.s19"), 0x10000).is_ok());
+        assert!(format.check_fits(Path::new("rom.s19"), 0x10001).is_err());
+        assert!(format.check_fits(Path::new("rom.srec"), 0x10001).is_ok());
+    }
+}
@@ -1,39 +1,1010 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::{Parser, Subcommand};
-use indicatif;
-use indicatif::ProgressBar;
-use indicatif::ProgressStyle;
+use std::collections::BTreeMap;
 use std::fs;
-use std::iter;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use indicatif::ProgressBar;
 
 use picolink::*;
 
 mod rom_size;
 use crate::rom_size::*;
 
-fn read_file(name: &Path, rom_size: RomSize) -> Result<Vec<u8>> {
-    let mut data = fs::read(name)?;
+mod ui;
+use crate::ui::*;
+
+mod parse;
+use crate::parse::*;
+
+mod config;
+use crate::config::*;
+
+mod regions;
+use crate::regions::*;
+
+mod pattern;
+use crate::pattern::*;
+
+mod formats;
+use crate::formats::*;
+
+mod verify;
+
+/// Read a ROM image, padding it to `rom_size` and mirroring it to fill the
+/// device's `buffer_size` bytes of ROM buffer. Intel HEX and S-record files are
+/// decoded first.
+fn read_file(name: &Path, rom_size: RomSize, buffer_size: usize) -> Result<Vec<u8>> {
+    pad_image(name, read_image(name)?, rom_size, buffer_size)
+}
+
+/// Like `read_file`, for an image about to be uploaded as a `rom_size` ROM.
+/// Also warns if a much smaller ROM size would hold it.
+fn read_upload_file(
+    ui: &Ui,
+    name: &Path,
+    rom_size: RomSize,
+    buffer_size: usize,
+) -> Result<Vec<u8>> {
+    let data = read_image(name)?;
+    // A tiny image in a large ROM is usually the wrong size selection, unless
+    // it is already the smallest size
+    if data.len() * 4 < rom_size.bytes() {
+        if let Some(suggested) =
+            RomSize::smallest_fitting(data.len()).filter(|x| x.bytes() != rom_size.bytes())
+        {
+            ui.warn(format!(
+                "{:?} ({} bytes) fills less than 25% of the selected {} ROM ({} bytes).\n         \
+                 Consider a smaller size such as {}.",
+                name,
+                data.len(),
+                rom_size,
+                rom_size.bytes(),
+                suggested
+            ));
+        }
+    }
+    pad_image(name, data, rom_size, buffer_size)
+}
+
+/// Pad `data`, read from `name`, to `rom_size` and mirror it to fill `buffer_size`
+fn pad_image(
+    name: &Path,
+    mut data: Vec<u8>,
+    rom_size: RomSize,
+    buffer_size: usize,
+) -> Result<Vec<u8>> {
     if data.len() > rom_size.bytes() {
         return Err(anyhow!(
-            "{:?} larger ({}) than rom size ({})",
+            "{:?} larger ({} bytes) than a {} ROM ({} bytes)",
             name,
             data.len(),
+            rom_size,
             rom_size.bytes()
         ));
     }
 
     let diff = rom_size.bytes() - data.len();
-    data.extend(iter::repeat(0u8).take(diff));
+    data.extend(std::iter::repeat_n(0u8, diff));
+
+    Ok(data.repeat((buffer_size / rom_size.bytes()).max(1)))
+}
+
+/// Read a window of a file, `len` bytes from `offset` or the rest of the file.
+/// The file is memory mapped so only the requested part of a large file is read.
+fn read_file_window(name: &Path, offset: u32, len: Option<u32>) -> Result<Vec<u8>> {
+    let file = fs::File::open(name)?;
+    // Safety: the mapping is only alive while the window is copied out of it
+    let map = unsafe { memmap2::Mmap::map(&file)? };
+
+    let start = offset as usize;
+    let end = len.map_or(map.len().max(start), |x| start + x as usize);
+    if start > map.len() || end > map.len() {
+        return Err(anyhow!(
+            "{:?} is only {} bytes, can't read 0x{:x}-0x{:x}",
+            name,
+            map.len(),
+            start,
+            end
+        ));
+    }
+
+    Ok(map[start..end].to_vec())
+}
+
+/// Check the device can emulate a ROM of `size`, returning its ROM buffer size
+fn check_rom_size(pico: &mut PicoLink, size: RomSize) -> Result<usize> {
+    let buffer_size = pico.max_rom_size();
+    if size.bytes() > buffer_size {
+        let supported: Vec<String> = RomSize::supported(buffer_size)
+            .map(|x| x.to_string())
+            .collect();
+        return Err(anyhow!(
+            "This PicoROM can't emulate a {} ROM, supported sizes are {}",
+            size,
+            supported.join(", ")
+        ));
+    }
+    Ok(buffer_size)
+}
+
+/// Upload a full ROM buffer image and set the address mask for `size`
+fn upload_data(ui: &Ui, pico: &mut PicoLink, data: &[u8], size: RomSize) -> Result<()> {
+    let progress = ui.progress_bar(data.len() as u64, "Uploading ROM");
+    let start = Instant::now();
+    write_image(pico, data, size, &progress)?;
+    progress.finish_with_message("Done.");
+    ui.timing("Upload", start, Some(data.len()));
+    Ok(())
+}
+
+/// Upload `data`, reporting progress on `progress`, then set the address mask
+/// for `size` and record the image's hash
+fn write_image(
+    pico: &mut PicoLink,
+    data: &[u8],
+    size: RomSize,
+    progress: &ProgressBar,
+) -> Result<()> {
+    pico.upload(data, |x| progress.inc(x as u64))?;
+    pico.set_addr_mask(size.mask())?;
+    // Firmware without rom_hash just can't skip unchanged uploads later
+    if pico
+        .set_parameter("rom_hash", &image_hash(data, size))
+        .is_err()
+    {
+        log::debug!("Device doesn't support rom_hash");
+    }
+    Ok(())
+}
+
+/// Upload the file in `dir` named after the device's rom_name, at `size` or the
+/// device's current ROM size. Returns the path of the uploaded file.
+fn upload_named_rom(
+    ui: &Ui,
+    devices: &Devices,
+    info: &PicoInfo,
+    dir: &Path,
+    size: Option<RomSize>,
+    store: bool,
+    progress: &ProgressBar,
+) -> Result<PathBuf> {
+    let mut pico = devices.open(info)?;
+    let rom_name = pico.get_parameter("rom_name")?;
+    // rom_name is normally a bare file name, don't let it reach outside `dir`
+    if rom_name.is_empty() || Path::new(&rom_name).file_name() != Some(rom_name.as_ref()) {
+        return Err(anyhow!("rom_name '{}' isn't a file name", rom_name));
+    }
+    let source = dir.join(&rom_name);
+    let size = match size {
+        Some(size) => size,
+        None => current_rom_size(&mut pico, None)?,
+    };
+    let buffer_size = check_rom_size(&mut pico, size)?;
+    let data = read_upload_file(ui, &source, size, buffer_size)?;
+
+    progress.set_length(data.len() as u64);
+    write_image(&mut pico, &data, size, progress)?;
+    if store {
+        progress.set_message("Storing");
+        pico.commit_rom_verified()?;
+    }
+    progress.finish_with_message("Done.");
+    Ok(source)
+}
+
+/// Upload to every device whose name matches `pattern` at once, one thread
+/// each, then print how each one went
+fn upload_all(
+    ui: &Ui,
+    devices: &Devices,
+    pattern: &str,
+    dir: &Path,
+    size: Option<RomSize>,
+    store: bool,
+) -> Result<()> {
+    let glob = glob::Pattern::new(pattern)
+        .with_context(|| format!("Invalid device name pattern '{}'", pattern))?;
+    let found: Vec<PicoInfo> = devices
+        .enumerate()?
+        .into_iter()
+        .filter(|x| glob.matches(&x.name))
+        .collect();
+    if found.is_empty() {
+        return Err(PicoError::NotFound(pattern.to_string()).into());
+    }
+
+    let multi = ui.multi_progress();
+    let bars: Vec<ProgressBar> = found
+        .iter()
+        .map(|x| multi.add(ui.progress_bar(0, x.name.clone())))
+        .collect();
+    let start = Instant::now();
+    let results: Vec<Result<PathBuf>> = std::thread::scope(|scope| {
+        let threads: Vec<_> = found
+            .iter()
+            .zip(&bars)
+            .map(|(info, bar)| {
+                scope.spawn(move || {
+                    let result = upload_named_rom(ui, devices, info, dir, size, store, bar);
+                    if result.is_err() {
+                        bar.abandon_with_message("Failed.");
+                    }
+                    result
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|x| {
+                x.join()
+                    .unwrap_or_else(|_| Err(anyhow!("Upload thread panicked")))
+            })
+            .collect()
+    });
+    ui.timing("Upload", start, None);
+
+    let mut failed = 0;
+    for (info, result) in found.iter().zip(&results) {
+        match result {
+            Ok(source) => println!("  {:16} uploaded {:?}", info.name, source),
+            Err(e) => {
+                failed += 1;
+                println!("  {:16} failed: {:#}", info.name, e);
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} of {} uploads failed", failed, found.len()));
+    }
+    Ok(())
+}
+
+/// Short hash identifying an uploaded image and its ROM size, stored in the
+/// device's rom_hash parameter. The device clears it when the ROM changes.
+fn image_hash(data: &[u8], size: RomSize) -> String {
+    use sha2::{Digest, Sha256};
+    let digest = Sha256::new()
+        .chain_update(data)
+        .chain_update(size.mask().to_le_bytes())
+        .finalize();
+    digest[..8].iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Upload a ROM image file, set the address mask to match and record its name.
+/// With `if_changed`, nothing is uploaded if the device reports it already has
+/// this image, and false is returned. With `verify`, the ROM buffer is read
+/// back and compared with the image, whether it was uploaded or not.
+fn upload_image(
+    ui: &Ui,
+    pico: &mut PicoLink,
+    source: &Path,
+    size: RomSize,
+    if_changed: bool,
+    verify: bool,
+) -> Result<bool> {
+    let buffer_size = check_rom_size(pico, size)?;
+    let data = read_upload_file(ui, source, size, buffer_size)?;
+    let changed = !if_changed
+        || pico.get_parameter("rom_hash").unwrap_or_default() != image_hash(&data, size);
+    if changed {
+        upload_data(ui, pico, &data, size)?;
+        if let Some(filename) = source.file_name() {
+            pico.set_parameter("rom_name", filename.to_string_lossy().as_ref())?;
+        }
+    }
+    // Writes aren't acknowledged, so this is the only check that they all landed
+    if verify {
+        verify_rom(ui, pico, &data)?;
+    }
+    Ok(changed)
+}
+
+/// Upload a generated test pattern filling a ROM of `size`, mirrored like a file image
+fn upload_pattern(ui: &Ui, pico: &mut PicoLink, pattern: Pattern, size: RomSize) -> Result<()> {
+    let buffer_size = check_rom_size(pico, size)?;
+    let data = pattern
+        .generate(size.bytes())
+        .repeat((buffer_size / size.bytes()).max(1));
+    upload_data(ui, pico, &data, size)?;
+    pico.set_parameter("rom_name", &format!("{} pattern", pattern.name()))?;
+    Ok(())
+}
+
+/// Read back `expected.len()` bytes of ROM and compare them with `expected`,
+/// printing the first difference and failing with `PicoError::VerifyMismatch`
+fn verify_rom(ui: &Ui, pico: &mut PicoLink, expected: &[u8]) -> Result<()> {
+    let progress = ui.progress_bar(expected.len() as u64, "Verifying");
+    let start = Instant::now();
+    let actual = pico.download(expected.len(), |x| progress.inc(x as u64))?;
+    progress.finish_with_message("Done.");
+    ui.timing("Verify", start, Some(actual.len()));
+
+    if let Some(offset) = verify::first_mismatch(expected, &actual) {
+        verify::print_mismatch(expected, &actual, offset, 0);
+        return Err(PicoError::VerifyMismatch(format!(
+            "ROM differs from the expected image at 0x{:x}",
+            offset
+        ))
+        .into());
+    }
+    if actual.len() != expected.len() {
+        return Err(PicoError::VerifyMismatch(format!(
+            "Read back {} of {} bytes",
+            actual.len(),
+            expected.len()
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// The ROM size the device is set to, checked against `size` if one was given
+fn current_rom_size(pico: &mut PicoLink, size: Option<RomSize>) -> Result<RomSize> {
+    let current = pico.rom_size()?;
+    match size {
+        Some(size) if size.bytes() != current => Err(PicoError::VerifyMismatch(format!(
+            "Device is set to a {} byte ROM, not {}",
+            current, size
+        ))
+        .into()),
+        Some(size) => Ok(size),
+        None => RomSize::from_bytes(current)
+            .ok_or_else(|| anyhow!("Device's ROM size ({} bytes) isn't a known size", current)),
+    }
+}
+
+/// CRC32 of the first `len` bytes of ROM. Firmware that can't compute it has
+/// the ROM read back instead.
+fn rom_checksum(ui: &Ui, pico: &mut PicoLink, len: usize) -> Result<u32> {
+    if pico.can_checksum() {
+        return pico.checksum(0, len as u32);
+    }
+    log::debug!("Firmware can't compute checksums, reading the ROM back");
+    let progress = ui.progress_bar(len as u64, "Reading ROM");
+    let data = pico.download(len, |x| progress.inc(x as u64))?;
+    progress.finish_with_message("Done.");
+    Ok(crc32fast::hash(&data))
+}
+
+/// Print the SHA-256 and CRC32 of `data`, for recording where an image came from
+fn print_hashes(data: &[u8]) {
+    use sha2::{Digest, Sha256};
+    let sha256: String = Sha256::digest(data)
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect();
+    println!("SHA-256: {}", sha256);
+    println!("CRC32:   {:08x}", crc32fast::hash(data));
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String)> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected name=value, got '{}'", s))?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Warn about devices other than `name` whose rom_name is already `rom_name`.
+/// Devices that can't be opened are skipped.
+fn warn_rom_name_in_use(ui: &Ui, devices: &Devices, name: &str, rom_name: &str) -> Result<()> {
+    for info in devices.enumerate()? {
+        if info.matches(name) {
+            continue;
+        }
+        // Opened by path, as devices can share a name
+        let other = devices
+            .open(&info)
+            .and_then(|mut pico| pico.get_parameter("rom_name"));
+        if other.is_ok_and(|x| x == rom_name) {
+            ui.warn(format!(
+                "'{}' already has {:?} loaded, check '{}' is the right device.",
+                info.name, rom_name, name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Parameters that differ between devices even when they are set up the same
+const PER_DEVICE_PARAMS: &[&str] = &["name", "uptime", "startup_time", "reset_reason"];
+
+/// Read every parameter the device reports
+fn read_parameters(pico: &mut PicoLink) -> Result<BTreeMap<String, String>> {
+    let mut values = BTreeMap::new();
+    for param in pico.get_parameters()? {
+        let value = pico.get_parameter(&param)?;
+        values.insert(param, value);
+    }
+    Ok(values)
+}
+
+/// Parameters left out of exported profiles. rom_hash describes the current
+/// image, so restoring it onto another image would hide that it changed.
+const UNEXPORTED_PARAMS: &[&str] = &["rom_hash"];
+
+/// Write every parameter to a TOML file of name = "value" lines
+fn export_parameters(pico: &mut PicoLink, path: &Path) -> Result<usize> {
+    let mut values = read_parameters(pico)?;
+    values.retain(|name, _| !UNEXPORTED_PARAMS.contains(&name.as_str()));
+    fs::write(path, toml::to_string(&values)?)
+        .with_context(|| format!("Could not write {:?}", path))?;
+    Ok(values.len())
+}
+
+/// Set the parameters in a file written by `export_parameters`, see `apply_parameters`
+fn import_parameters(
+    ui: &Ui,
+    pico: &mut PicoLink,
+    path: &Path,
+    exclude: &[String],
+) -> Result<Vec<String>> {
+    let text = fs::read_to_string(path).with_context(|| format!("Could not read {:?}", path))?;
+    let values: BTreeMap<String, String> =
+        toml::from_str(&text).with_context(|| format!("Could not parse {:?}", path))?;
+    apply_parameters(ui, pico, &values, exclude)
+}
+
+/// Set parameters, except those in `exclude` and those that already have the
+/// value. Ones the device rejects, such as read-only status values, are
+/// skipped and returned.
+fn apply_parameters(
+    ui: &Ui,
+    pico: &mut PicoLink,
+    values: &BTreeMap<String, String>,
+    exclude: &[String],
+) -> Result<Vec<String>> {
+    let current = read_parameters(pico)?;
+
+    let mut skipped = Vec::new();
+    for (name, value) in values {
+        if exclude.contains(name) || current.get(name) == Some(value) {
+            continue;
+        }
+        match pico.set_parameter(name, value) {
+            Ok(newvalue) => ui.info(format!("{}={}", name, newvalue)),
+            Err(e) if matches!(e.downcast_ref(), Some(PicoError::ParameterRejected(_))) => {
+                skipped.push(name.clone())
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(skipped)
+}
+
+/// Copy the ROM image and parameters of `src` to `dst`. The image is mirrored to
+/// fill the destination's ROM buffer, which may be a different size.
+fn clone_device(ui: &Ui, src: &mut PicoLink, dst: &mut PicoLink, include_name: bool) -> Result<()> {
+    let size = current_rom_size(src, None)?;
+    let mut values = read_parameters(src)?;
+    let progress = ui.progress_bar(size.bytes() as u64, "Downloading ROM");
+    let data = src.download(size.bytes(), |x| progress.inc(x as u64))?;
+    progress.finish_with_message("Done.");
+
+    let buffer_size = check_rom_size(dst, size)?;
+    upload_data(
+        ui,
+        dst,
+        &data.repeat((buffer_size / size.bytes()).max(1)),
+        size,
+    )?;
+
+    // The upload has set the address mask and image hash to match already
+    let mut exclude: Vec<String> = UNEXPORTED_PARAMS.iter().map(|x| x.to_string()).collect();
+    exclude.push("addr_mask".to_string());
+    if !include_name {
+        values.remove("name");
+    }
+    let skipped = apply_parameters(ui, dst, &values, &exclude)?;
+    if !skipped.is_empty() {
+        ui.info(format!(
+            "Skipped read-only parameters: {}",
+            skipped.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Describe a parameter change for --dry-run
+fn print_change(param: &str, current: &str, new: &str) {
+    if current == new {
+        println!("{}: '{}' (unchanged)", param, current);
+    } else {
+        println!("{}: '{}' -> '{}'", param, current, new);
+    }
+}
+
+fn commit_with_spinner(ui: &Ui, pico: &mut PicoLink) -> Result<()> {
+    let spinner = ui.spinner("Storing to Flash");
+    let start = Instant::now();
+    pico.commit_rom_verified()?;
+    spinner.finish_with_message("Done.");
+    ui.timing("Store", start, None);
+    Ok(())
+}
+
+/// Commit only the flash sectors covering a range, falling back to a full
+/// commit if anything outside it is still unsaved
+fn commit_range_with_spinner(ui: &Ui, pico: &mut PicoLink, addr: u32, len: u32) -> Result<()> {
+    let spinner = ui.spinner("Storing to Flash");
+    let start = Instant::now();
+    pico.commit_range(addr, len)?;
+    if pico.rom_dirty() == Some(true) {
+        pico.commit_rom_verified()?;
+    }
+    spinner.finish_with_message("Done.");
+    ui.timing("Store", start, None);
+    Ok(())
+}
+
+/// ROM size used when neither the command line nor the config file give one
+const DEFAULT_ROM_SIZE: RomSize = RomSize::MBit(2);
+
+/// Flash size of the RP2040 boards, for firmware that doesn't report it
+const DEFAULT_FLASH_SIZE: u32 = 2 * 1024 * 1024;
+
+/// The stored ROM occupies the top of flash, with a config sector below it
+const CONFIG_SECTOR_SIZE: u32 = 4096;
+
+fn print_flash_info(name: &str, pico: &mut PicoLink) -> Result<()> {
+    let flash_size = pico
+        .get_parameter_u32("flash_size")
+        .unwrap_or(DEFAULT_FLASH_SIZE);
+    // Older firmware stores the ROM buffer at the end of flash
+    let rom_offset = match pico.get_parameter_u32("rom_store_offset") {
+        Ok(offset) => Some(offset),
+        Err(_) => flash_size.checked_sub(pico.max_rom_size() as u32),
+    };
+    let (rom_offset, config_offset) = rom_offset
+        .filter(|x| *x <= flash_size)
+        .and_then(|x| Some((x, x.checked_sub(CONFIG_SECTOR_SIZE)?)))
+        .ok_or_else(|| {
+            anyhow!(
+                "Inconsistent flash layout: {} KiB of flash can't hold the ROM store and config",
+                flash_size / 1024
+            )
+        })?;
+    let firmware_size = pico.get_parameter_u32("firmware_size").ok();
+
+    let region = |label: &str, start: u32, end: u32| {
+        println!(
+            "  {:10} 0x{:06x}-0x{:06x} ({} KiB)",
+            label,
+            start,
+            end,
+            (end - start) / 1024
+        );
+    };
+
+    println!("Flash layout of '{}' ({} KiB):", name, flash_size / 1024);
+    if let Some(firmware_size) = firmware_size {
+        region("Firmware", 0, firmware_size);
+        region("Free", firmware_size, config_offset.max(firmware_size));
+    } else {
+        println!("  {:10} unknown (old firmware)", "Firmware");
+    }
+    region("Config", config_offset, rom_offset);
+    region("ROM store", rom_offset, flash_size);
+    Ok(())
+}
+
+/// Print the state of the device, skipping anything the firmware doesn't report
+fn print_status(name: &str, pico: &mut PicoLink) {
+    let row = |label: &str, value: String| println!("  {:13} {}", label, value);
+
+    println!("Status of '{}':", name);
+    if let Ok(rom_name) = pico.get_parameter("rom_name") {
+        row("ROM name", rom_name);
+    }
+    if let Ok(mask) = pico.get_addr_mask() {
+        row(
+            "ROM size",
+            format!(
+                "{} KiB (addr_mask 0x{:x})",
+                (mask as usize + 1) / 1024,
+                mask
+            ),
+        );
+    }
+    row(
+        "Max ROM size",
+        format!("{} KiB", pico.max_rom_size() / 1024),
+    );
+    if let Ok(reset) = pico.get_parameter("reset") {
+        row("Reset", reset);
+    }
+    if let Ok(uptime) = pico.get_parameter_u32("uptime") {
+        let uptime = Duration::from_secs(uptime as u64 / 1000);
+        row("Uptime", humantime::format_duration(uptime).to_string());
+    }
+    if let Ok(reason) = pico.get_parameter("reset_reason") {
+        row("Reset reason", reason);
+    }
+    if let Some(dirty) = pico.rom_dirty() {
+        let flash = if dirty {
+            "differs from the working image, run commit to store it"
+        } else {
+            "matches the working image"
+        };
+        row("Flash", flash.to_string());
+    }
+}
+
+/// Versions and identity of a device, in one block to paste into bug reports
+fn print_device_info(info: &PicoInfo, pico: &mut PicoLink) {
+    let row = |label: &str, value: String| println!("  {:17} {}", label, value);
+    let param = |pico: &mut PicoLink, name: &str| {
+        pico.get_parameter(name)
+            .unwrap_or_else(|_| "unknown (old firmware)".to_string())
+    };
 
-    Ok(data.repeat(RomSize::MBit(2).bytes() / rom_size.bytes()))
+    println!("Info for '{}':", info.name);
+    row(
+        "Serial number",
+        info.serial_number
+            .clone()
+            .unwrap_or_else(|| "unknown".to_string()),
+    );
+    row("Port", pico.path.clone());
+    row("Firmware version", param(pico, "build_version"));
+    row("Build config", param(pico, "build_config"));
+    row("Protocol version", pico.protocol_version().to_string());
+    row("ROM name", param(pico, "rom_name"));
+    match pico.get_addr_mask() {
+        Ok(mask) => {
+            row("ROM size", format!("{} KiB", (mask as usize + 1) / 1024));
+            row("Address mask", format!("0x{:x}", mask));
+        }
+        Err(_) => row("ROM size", "unknown".to_string()),
+    }
+    row(
+        "Max ROM size",
+        format!("{} KiB", pico.max_rom_size() / 1024),
+    );
+    row("picorom version", env!("CARGO_PKG_VERSION").to_string());
+}
+
+/// Wait after serve fails to accept a connection, so running out of file
+/// descriptors doesn't spin
+const ACCEPT_RETRY_WAIT: Duration = Duration::from_millis(100);
+
+/// How often watch checks its file for changes
+const WATCH_POLL: Duration = Duration::from_millis(100);
+
+/// Modification time and length of a file, to notice it being rewritten
+fn file_stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Hold the target in reset while `f` runs, then restore the level it was at before
+fn with_reset_held<T, F>(pico: &mut PicoLink, f: F) -> Result<T>
+where
+    F: FnOnce(&mut PicoLink) -> Result<T>,
+{
+    let level: ResetLevel = pico.get_parameter("reset")?.parse()?;
+    pico.reset(ResetLevel::Low)?;
+    let result = f(pico);
+    pico.reset(level)?;
+    result
+}
+
+/// Drive reset low for `duration`, then restore the level it was at before
+fn pulse_reset(pico: &mut PicoLink, duration: Duration) -> Result<()> {
+    let level: ResetLevel = pico.get_parameter("reset")?.parse()?;
+    pico.reset(ResetLevel::Low)?;
+    std::thread::sleep(duration);
+    pico.reset(level)
+}
+
+/// Drive reset through each level, describing what the target should do at each,
+/// then restore the level it was at before
+fn test_reset(pico: &mut PicoLink, pause: Option<Duration>) -> Result<()> {
+    let steps = [
+        (ResetLevel::Low, "the target should be held in reset"),
+        (ResetLevel::High, "the target should be running"),
+        (
+            ResetLevel::Z,
+            "the pin is released, the target's own reset circuit is in control",
+        ),
+    ];
+
+    let original: ResetLevel = pico.get_parameter("reset")?.parse()?;
+    for (level, expected) in steps {
+        pico.reset(level)?;
+        println!("Reset {}: {}.", level, expected);
+        match pause {
+            Some(pause) => std::thread::sleep(pause),
+            None => {
+                eprint!("Press Enter to continue...");
+                std::io::stdin().read_line(&mut String::new())?;
+            }
+        }
+    }
+    pico.reset(original)?;
+    println!("Reset restored to {}.", original);
+    Ok(())
+}
+
+/// Send data over comms and collect the response until nothing arrives for `timeout`
+fn comms_exchange(pico: &mut PicoLink, data: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+    let mut response = pico.poll_comms(Some(data.to_vec()))?;
+    loop {
+        let incoming = pico.poll_comms_blocking(timeout)?;
+        if incoming.is_empty() {
+            break;
+        }
+        response.extend_from_slice(&incoming);
+    }
+    Ok(response)
+}
+
+/// Bytes sent per exchange in the comms loopback test
+const COMMS_TEST_CHUNK: usize = 256;
+
+/// Send `len` pseudo-random bytes over comms and check they all come back in
+/// order, as a target running a loopback program sends them. Each wait for
+/// echoed data gives up after `timeout` without any arriving.
+fn comms_loopback_test(ui: &Ui, pico: &mut PicoLink, len: usize, timeout: Duration) -> Result<()> {
+    // xorshift32, so runs are repeatable and don't look like real traffic
+    let mut state = 0x2545f491u32;
+    let sent: Vec<u8> = (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+
+    let progress = ui.progress_bar(len as u64, "Testing");
+    let start = Instant::now();
+    let mut received = Vec::with_capacity(len);
+    for chunk in sent.chunks(COMMS_TEST_CHUNK) {
+        received.extend(pico.poll_comms(Some(chunk.to_vec()))?);
+        progress.set_position(received.len().min(len) as u64);
+    }
+    while received.len() < len {
+        let incoming = pico.poll_comms_blocking(timeout)?;
+        if incoming.is_empty() {
+            break;
+        }
+        received.extend(incoming);
+        progress.set_position(received.len().min(len) as u64);
+    }
+    let elapsed = start.elapsed();
+    progress.finish_with_message("Done.");
+
+    if let Some(offset) = verify::first_mismatch(&sent, &received) {
+        verify::print_mismatch(&sent, &received, offset, 0);
+        return Err(PicoError::VerifyMismatch(format!(
+            "Echoed data differs from what was sent at byte {}",
+            offset
+        ))
+        .into());
+    }
+    if received.len() < len {
+        return Err(anyhow!(PicoError::Timeout).context(format!(
+            "Only {} of {} bytes were echoed",
+            received.len(),
+            len
+        )));
+    }
+    if received.len() > len {
+        return Err(PicoError::VerifyMismatch(format!(
+            "{} more bytes were echoed than sent",
+            received.len() - len
+        ))
+        .into());
+    }
+
+    println!(
+        "Echoed {} bytes OK in {:.2}s ({:.0} bytes/s)",
+        len,
+        elapsed.as_secs_f64(),
+        len as f64 / elapsed.as_secs_f64().max(0.001)
+    );
+    Ok(())
+}
+
+/// How long pipe waits for comms data before checking stdin again
+const PIPE_POLL: Duration = Duration::from_millis(10);
+
+/// How long pipe keeps forwarding the target's output after stdin closes
+const PIPE_LINGER: Duration = Duration::from_millis(200);
+
+/// Forward comms data to stdout and stdin to comms until stdin closes or
+/// `running` is cleared
+fn comms_pipe(pico: &mut PicoLink, running: &AtomicBool) -> Result<()> {
+    use std::io::{Read, Write};
+    use std::sync::mpsc::{self, TryRecvError};
+
+    // Reading stdin blocks, so it has its own thread. The channel closing means EOF.
+    let (tx, rx) = mpsc::channel::<Vec<u8>>();
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin().lock();
+        let mut buf = [0u8; 256];
+        while let Ok(len @ 1..) = stdin.read(&mut buf) {
+            if tx.send(buf[..len].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+
+    // Written and flushed as it arrives, so interactive use isn't line buffered
+    let mut stdout = std::io::stdout().lock();
+    let mut eof = false;
+    while running.load(Ordering::SeqCst) && !eof {
+        let mut outgoing = Vec::new();
+        loop {
+            match rx.try_recv() {
+                Ok(data) => outgoing.extend(data),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    eof = true;
+                    break;
+                }
+            }
+        }
+        let incoming = if outgoing.is_empty() {
+            pico.poll_comms_blocking(PIPE_POLL)?
+        } else {
+            pico.poll_comms(Some(outgoing))?
+        };
+        stdout.write_all(&incoming)?;
+        stdout.flush()?;
+    }
+
+    // Pass on the target's response to the last of the input
+    loop {
+        let incoming = pico.poll_comms_blocking(PIPE_LINGER)?;
+        if incoming.is_empty() || !running.load(Ordering::SeqCst) {
+            break;
+        }
+        stdout.write_all(&incoming)?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+/// Run the `upload --exec` command through the shell, describing the upload in its environment
+fn run_upload_hook(command: &str, name: &str, size: RomSize) -> Result<()> {
+    let mut shell = if cfg!(windows) {
+        let mut cmd = std::process::Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    } else {
+        let mut cmd = std::process::Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+
+    let status = shell
+        .arg(command)
+        .env("PICOROM_DEVICE", name)
+        .env("PICOROM_ROM_SIZE", size.to_string())
+        .env("PICOROM_ROM_BYTES", size.bytes().to_string())
+        .status()
+        .with_context(|| format!("Failed to run '{}'", command))?;
+
+    if !status.success() {
+        return Err(anyhow!("'{}' failed: {}", command, status));
+    }
+    Ok(())
+}
+
+/// Where devices are found: attached to this machine, or through a remote `picorom serve`
+struct Devices {
+    baud: u32,
+    remote: Option<String>,
+    /// Device from the config file, used when no name is given
+    default_device: Option<String>,
+}
+
+impl Devices {
+    fn find(&self, name: &str) -> Result<PicoLink> {
+        match &self.remote {
+            Some(addr) => find_remote_pico(addr, name),
+            None => find_pico_with_baud(name, self.baud),
+        }
+    }
+
+    /// Open a device found by `enumerate`
+    fn open(&self, info: &PicoInfo) -> Result<PicoLink> {
+        match &self.remote {
+            Some(addr) => find_remote_pico(addr, &info.name),
+            None => PicoLink::open_with_baud(&info.path, self.baud),
+        }
+    }
+
+    fn enumerate(&self) -> Result<Vec<PicoInfo>> {
+        match &self.remote {
+            Some(addr) => enumerate_remote(addr),
+            None => enumerate_picos_with_baud(self.baud),
+        }
+    }
+
+    /// Pick the device to use when no name was given on the command line.
+    /// Falls back to $PICOROM_DEVICE, the config file's device, then to the
+    /// only connected PicoROM.
+    fn resolve(&self, name: Option<String>) -> Result<String> {
+        if let Some(name) = name {
+            return Ok(name);
+        }
+
+        if let Ok(name) = std::env::var("PICOROM_DEVICE") {
+            if !name.is_empty() {
+                return Ok(name);
+            }
+        }
+
+        if let Some(name) = &self.default_device {
+            return Ok(name.clone());
+        }
+
+        let found = self.enumerate()?;
+        match found.as_slice() {
+            [] => Err(PicoError::NoDevices.into()),
+            [info] => Ok(info.name.clone()),
+            _ => Err(anyhow!(
+                "Multiple PicoROMs found, specify a name or set PICOROM_DEVICE: {}",
+                found
+                    .iter()
+                    .map(|x| x.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        }
+    }
 }
 
 #[derive(Debug, Parser)] // requires `derive` feature
 #[command(name = "picorom")]
 #[command(about = "PicoROM controller", long_about = None)]
+#[command(
+    after_help = "Devices that share a name can be given as id:<serial> or port:<path> \
+                  instead, as shown by list.\n\n\
+                  Exit codes: 0 success, 1 other error, 2 device not found, 3 timeout or \
+                  disconnect, 4 verify mismatch, 5 permission denied, 6 device in use by \
+                  another process"
+)]
 struct Cli {
+    /// When to use colored output. Auto respects NO_COLOR. Defaults to the
+    /// config file's color, or auto.
+    #[arg(long, global = true, value_enum)]
+    color: Option<ColorChoice>,
+
+    /// Print more detail, such as the port each device was opened on.
+    /// Repeat for debug (-vv) and trace (-vvv) logging. RUST_LOG overrides this.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Only print results and errors, without progress bars or status messages.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Print the results of list and get as JSON, for scripts.
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Baud rate to request when opening the serial port. USB serial ignores it,
+    /// but some host drivers don't.
+    #[arg(long, global = true, default_value_t = DEFAULT_BAUD)]
+    baud: u32,
+
+    /// Use the PicoROMs attached to another machine running `picorom serve`,
+    /// given as host or host:port.
+    #[arg(long, global = true, value_name = "HOST:PORT", env = "PICOROM_REMOTE")]
+    remote: Option<String>,
+
+    /// How long to wait for each response from a device, such as 500ms. Raise
+    /// it if slow or heavily loaded hosts see spurious timeouts. Defaults to 100ms.
+    #[arg(long, global = true, value_parser = humantime::parse_duration)]
+    response_timeout: Option<Duration>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -45,14 +1016,22 @@ enum Commands {
 
     /// Flash the activity LED on a specific PicoRom
     Identify {
-        /// PicoROM device name.
-        name: String,
+        /// PicoROM device name. Defaults to $PICOROM_DEVICE, the config file's device
+        /// or the only connected device.
+        name: Option<String>,
+        /// Keep re-sending the request until Ctrl-C is pressed.
+        #[arg(short, long, default_value_t = false)]
+        repeat: bool,
+        /// Time between requests when repeating. Each request flashes the LED for about 5s.
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "5s")]
+        interval: Duration,
     },
 
     /// Commit the current ROM image to flash memory
     Commit {
-        /// PicoROM device name.
-        name: String,
+        /// PicoROM device name. Defaults to $PICOROM_DEVICE, the config file's device
+        /// or the only connected device.
+        name: Option<String>,
     },
 
     /// Change the name of a PicoROM device.
@@ -61,30 +1040,336 @@ enum Commands {
         current: String,
         /// New name to rename it to.
         new: String,
+        /// Show the change without making it.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
     },
 
     /// Upload a ROM image to a PicoROM
     Upload {
+        /// PicoROM device name.
+        name: String,
+        /// Path of file to upload. .hex, .ihx and .ihex files are decoded as Intel
+        /// HEX, .srec, .s19, .s28, .s37 and .mot files as S-records, and anything
+        /// else is uploaded as raw binary.
+        source: PathBuf,
+        /// Emulate a specific ROM size. Sizes above 2MBit need hardware that supports them.
+        /// Defaults to the config file's size, or the smallest size that holds the file.
+        /// The image is zero padded to this size and repeated to fill the ROM buffer,
+        /// so it is mirrored at every multiple of the size.
+        #[arg(value_enum, ignore_case = true)]
+        size: Option<RomSize>,
+        /// Store the uploaded image in flash memory also.
+        #[arg(short, long, default_value_t = false)]
+        store: bool,
+        /// Shell command to run after a successful upload. It gets PICOROM_DEVICE,
+        /// PICOROM_ROM_SIZE and PICOROM_ROM_BYTES in its environment.
+        #[arg(long, value_name = "COMMAND")]
+        exec: Option<String>,
+        /// Warn if another connected PicoROM already has this image loaded,
+        /// which often means the wrong device was picked.
+        #[arg(long, default_value_t = false)]
+        check_unique: bool,
+        /// Skip the upload if the device reports it already has this image at
+        /// this size. --store and --exec still run.
+        #[arg(long, default_value_t = false)]
+        if_changed: bool,
+        /// Read the ROM back after uploading and fail if it differs from the image.
+        #[arg(long, default_value_t = false)]
+        verify: bool,
+        /// Hold the target in reset during the upload and --store, so it
+        /// restarts with the new image. Reset is released even if they fail.
+        #[arg(long, default_value_t = false)]
+        reset_cycle: bool,
+        /// Reset level held during --reset-cycle: high, low or z.
+        #[arg(long, default_value = "low", requires = "reset_cycle")]
+        reset_hold: ResetLevel,
+        /// Reset level set after --reset-cycle: high, low or z.
+        #[arg(long, default_value = "z", requires = "reset_cycle")]
+        reset_release: ResetLevel,
+    },
+
+    /// Upload a ROM image, then upload it again whenever the file changes
+    Watch {
         /// PicoROM device name.
         name: String,
         /// Path of file to upload.
         source: PathBuf,
-        /// Emulate a specific ROM size.
-        #[arg(value_enum, ignore_case=true, default_value_t=RomSize::MBit(2))]
-        size: RomSize,
+        /// Emulate a specific ROM size. Defaults to the config file's size, or the
+        /// smallest size that holds the file each time it is uploaded.
+        /// The image is zero padded to this size and repeated to fill the ROM buffer,
+        /// so it is mirrored at every multiple of the size.
+        #[arg(value_enum, ignore_case = true)]
+        size: Option<RomSize>,
+        /// Store each upload in flash memory also.
+        #[arg(long, default_value_t = false)]
+        commit: bool,
+        /// Hold the target in reset during each upload, so it restarts cleanly
+        /// with the new image.
+        #[arg(long, default_value_t = false)]
+        reset: bool,
+        /// How long the file must stay unchanged before it is uploaded, so a
+        /// build that writes it in several steps only uploads once.
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "200ms")]
+        debounce: Duration,
+    },
+
+    /// Upload to every PicoROM whose name matches a pattern, all at once. Each
+    /// gets the file in a directory named after its current rom_name.
+    UploadAll {
+        /// Glob matched against device names, e.g. 'cab1-*'.
+        pattern: String,
+        /// Directory holding the ROM images.
+        dir: PathBuf,
+        /// Emulate a specific ROM size on every device. Defaults to each
+        /// device's current ROM size.
+        /// The image is zero padded to this size and repeated to fill the ROM buffer,
+        /// so it is mirrored at every multiple of the size.
+        #[arg(value_enum, ignore_case = true)]
+        size: Option<RomSize>,
+        /// Store the uploaded images in flash memory also.
+        #[arg(short, long, default_value_t = false)]
+        store: bool,
+    },
+
+    /// Upload a generated test image, so reads from the target show whether
+    /// its addressing is correct
+    UploadPattern {
+        /// PicoROM device name.
+        name: String,
+        /// Pattern to generate.
+        #[arg(long, value_enum)]
+        pattern: Pattern,
+        /// Emulate a specific ROM size. Sizes above 2MBit need hardware that supports them.
+        /// Defaults to the config file's size, or 2MBit.
+        #[arg(value_enum, ignore_case = true)]
+        size: Option<RomSize>,
         /// Store the uploaded image in flash memory also.
         #[arg(short, long, default_value_t = false)]
         store: bool,
     },
 
+    /// Write a file into part of the ROM image, leaving the rest untouched
+    Patch {
+        /// PicoROM device name.
+        name: String,
+        /// Address to start writing at, e.g. 0x1f000 or 124k.
+        #[arg(value_parser = parse_addr)]
+        addr: u32,
+        /// Path of file to write.
+        source: PathBuf,
+        /// Offset into the source file to start reading from.
+        #[arg(long, value_parser = parse_addr)]
+        skip: Option<u32>,
+        /// Number of bytes to take from the source file, defaults to the rest of it.
+        #[arg(long, value_parser = parse_size)]
+        len: Option<u32>,
+        /// Named region from the config file. The address is relative to its start
+        /// and the patch must fit inside it.
+        #[arg(long)]
+        region: Option<String>,
+        /// Store the patched image in flash memory also.
+        #[arg(short, long, default_value_t = false)]
+        store: bool,
+    },
+
+    /// Download the ROM image from a PicoROM to a file, e.g. to capture what the
+    /// target wrote into it
+    #[command(visible_alias = "save")]
+    Download {
+        /// PicoROM device name.
+        name: String,
+        /// Path of file to write.
+        dest: PathBuf,
+        /// Address to start reading from, e.g. 0x1f000 or 124k.
+        #[arg(long, value_parser = parse_addr, default_value_t = 0)]
+        offset: u32,
+        /// Number of bytes to read, defaults to the rest of the ROM.
+        #[arg(long, value_parser = parse_size)]
+        len: Option<u32>,
+        /// Named region from the config file to read. --offset and --len are
+        /// relative to it.
+        #[arg(long)]
+        region: Option<String>,
+        /// Read the device's whole ROM buffer instead of stopping at the current
+        /// ROM size, including mirrored and stale data past it.
+        #[arg(
+            long,
+            visible_alias = "raw",
+            conflicts_with = "region",
+            default_value_t = false
+        )]
+        full: bool,
+        /// Hold the target in reset while reading. The image only changes through
+        /// host writes and comms traffic, so this gives a consistent snapshot.
+        #[arg(long, default_value_t = false)]
+        snapshot: bool,
+        /// Also print the SHA-256 and CRC32 of the downloaded data.
+        #[arg(long, default_value_t = false)]
+        hash: bool,
+        /// File format to write. Defaults from the extension: .hex/.ihx for
+        /// Intel HEX, .srec/.s19/.s28/.s37/.mot for S-records, otherwise binary.
+        #[arg(long, value_enum)]
+        format: Option<ImageFormat>,
+    },
+
+    /// Read back the ROM and compare it with a file
+    Verify {
+        /// PicoROM device name.
+        name: String,
+        /// Path of file to compare against, padded like upload pads it.
+        source: PathBuf,
+        /// ROM size the file was uploaded as. Defaults to the device's current
+        /// ROM size, and must match it.
+        #[arg(value_enum, ignore_case = true)]
+        size: Option<RomSize>,
+    },
+
+    /// Print the CRC32 of the ROM, computed by the device, and optionally compare
+    /// it with a file. Much faster than verify, but only says whether they differ.
+    Checksum {
+        /// PicoROM device name.
+        name: String,
+        /// Path of file to compare against, padded like upload pads it.
+        source: Option<PathBuf>,
+        /// ROM size the file was uploaded as. Defaults to the device's current
+        /// ROM size, and must match it.
+        #[arg(value_enum, ignore_case = true)]
+        size: Option<RomSize>,
+    },
+
+    /// Read a window of the ROM and print it as a hexdump
+    Read {
+        /// PicoROM device name.
+        name: String,
+        /// Address to start reading from, e.g. 0x1f000 or 124k.
+        #[arg(value_parser = parse_addr)]
+        offset: u32,
+        /// Number of bytes to read, e.g. 256 or 4k.
+        #[arg(value_parser = parse_size)]
+        len: u32,
+    },
+
+    /// Show how the PicoROM's flash memory is laid out
+    FlashInfo {
+        /// PicoROM device name. Defaults to $PICOROM_DEVICE, the config file's device
+        /// or the only connected device.
+        name: Option<String>,
+    },
+
+    /// Print a device's name, serial number, firmware version and ROM setup,
+    /// for bug reports
+    Info {
+        /// PicoROM device name. Defaults to $PICOROM_DEVICE, the config file's device
+        /// or the only connected device.
+        name: Option<String>,
+    },
+
+    /// Upload an image, set parameters and commit over one connection,
+    /// stopping at the first step that fails
+    Provision {
+        /// PicoROM device name.
+        name: String,
+        /// ROM image to upload.
+        #[arg(long)]
+        image: Option<PathBuf>,
+        /// Emulate a specific ROM size for the image. Sizes above 2MBit need hardware
+        /// that supports them. Defaults to the config file's size, or the smallest
+        /// size that holds the image.
+        /// The image is zero padded to this size and repeated to fill the ROM buffer,
+        /// so it is mirrored at every multiple of the size.
+        #[arg(long, value_enum, ignore_case = true)]
+        size: Option<RomSize>,
+        /// Parameter to set, as name=value. May be repeated.
+        #[arg(long = "set", value_name = "NAME=VALUE", value_parser = parse_key_value)]
+        params: Vec<(String, String)>,
+        /// Store the result in flash memory.
+        #[arg(long, default_value_t = false)]
+        commit: bool,
+    },
+
+    /// Show a summary of the PicoROM's current state
+    Status {
+        /// PicoROM device name. Defaults to $PICOROM_DEVICE, the config file's device
+        /// or the only connected device.
+        name: Option<String>,
+    },
+
+    /// Fill the whole emulated ROM with a single value
+    Clear {
+        /// PicoROM device name. Defaults to $PICOROM_DEVICE, the config file's device
+        /// or the only connected device.
+        name: Option<String>,
+        /// Byte value to fill with.
+        #[arg(long, value_parser = parse_byte, default_value = "0")]
+        value: u8,
+    },
+
+    /// Exchange data with software on the target over the comms channel
+    Comms {
+        /// PicoROM device name.
+        name: String,
+        /// Address of the comms registers in the ROM.
+        #[arg(value_parser = parse_addr)]
+        addr: u32,
+        /// Hex bytes to send, e.g. "01 02 ff". Each use is a separate exchange.
+        #[arg(long = "send", value_name = "HEX", value_parser = parse_hex_bytes)]
+        exchanges: Vec<HexBytes>,
+        /// How long to wait for more response data in each exchange.
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "500ms")]
+        timeout: Duration,
+        /// Pulse the reset line low between exchanges to restart the target.
+        #[arg(long, default_value_t = false)]
+        reset_between: bool,
+        /// How long reset is held low for each pulse.
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "100ms")]
+        reset_pulse: Duration,
+    },
+
+    /// Check the comms channel end to end by sending pseudo-random data and
+    /// checking it is echoed back. The target must run a loopback program.
+    Commstest {
+        /// PicoROM device name.
+        name: String,
+        /// Address of the comms registers in the ROM.
+        #[arg(value_parser = parse_addr)]
+        addr: u32,
+        /// Number of bytes to send, e.g. 4096 or 64k.
+        #[arg(long, value_parser = parse_size, default_value = "4096")]
+        len: u32,
+        /// How long to wait for echoed data before giving up.
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "2s")]
+        timeout: Duration,
+    },
+
+    /// Connect stdin and stdout to the comms channel, until stdin closes or
+    /// Ctrl-C is pressed
+    Pipe {
+        /// PicoROM device name.
+        name: String,
+        /// Address of the comms registers in the ROM.
+        #[arg(value_parser = parse_addr)]
+        addr: u32,
+    },
+
     /// Set the level of the reset pin
     Reset {
         /// PicoROM device name.
         name: String,
 
-        /// Reset level
-        #[arg(value_parser = clap::builder::PossibleValuesParser::new(["high", "low", "z"]))]
-        level: String,
+        /// Reset level: high, low or z
+        level: ResetLevel,
+    },
+
+    /// Step the reset pin through low, high and z so the target's response
+    /// can be checked at each level
+    TestReset {
+        /// PicoROM device name.
+        name: String,
+
+        /// Wait this long at each level instead of waiting for Enter.
+        #[arg(long, value_parser = humantime::parse_duration)]
+        pause: Option<Duration>,
     },
 
     /// Get the value of a parameter
@@ -106,93 +1391,667 @@ enum Commands {
 
         /// Parameter value
         value: String,
+
+        /// Show the current and new value without changing anything.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+
+    /// Compare the parameters of two PicoROMs and print the ones that differ
+    DiffConfig {
+        /// First PicoROM device name.
+        first: String,
+        /// Second PicoROM device name.
+        second: String,
+        /// Include parameters that are expected to differ between devices,
+        /// such as name and uptime.
+        #[arg(long, default_value_t = false)]
+        all: bool,
+    },
+
+    /// Save a PicoROM's parameters to a TOML file, to restore or copy to
+    /// another device with import-params
+    ExportParams {
+        /// PicoROM device name.
+        name: String,
+        /// Path of file to write.
+        file: PathBuf,
+    },
+
+    /// Set a PicoROM's parameters from a file written by export-params.
+    /// Parameters the device won't set, such as status values, are skipped.
+    ImportParams {
+        /// PicoROM device name.
+        name: String,
+        /// Path of file to read.
+        file: PathBuf,
+        /// Parameter to leave unchanged, e.g. name when copying settings to
+        /// another device. May be repeated.
+        #[arg(long, value_name = "PARAM")]
+        exclude: Vec<String>,
+        /// Store the new settings in flash memory.
+        #[arg(long, default_value_t = false)]
+        commit: bool,
+    },
+
+    /// Copy one PicoROM's ROM image and parameters to another
+    Clone {
+        /// PicoROM device to copy from.
+        src: String,
+        /// PicoROM device to copy to.
+        dst: String,
+        /// Copy the name too. Two devices with the same name can't be told apart.
+        #[arg(long, default_value_t = false)]
+        include_name: bool,
+        /// Store the image and settings in the destination's flash memory also.
+        #[arg(short, long, default_value_t = false)]
+        store: bool,
     },
 
     /// Reboot the device into USB mode
-    USBBoot { name: String },
+    USBBoot {
+        /// PicoROM device name. Defaults to $PICOROM_DEVICE, the config file's device
+        /// or the only connected device.
+        name: Option<String>,
+    },
+
+    /// Share the PicoROMs attached to this machine with `--remote` clients.
+    /// There is no authentication, so only listen on trusted networks.
+    Serve {
+        /// Address to listen on.
+        #[arg(long, default_value_t = format!("127.0.0.1:{}", DEFAULT_SERVE_PORT))]
+        listen: String,
+    },
 }
 
-fn main() -> Result<()> {
+/// Exit codes for the failures scripts may want to tell apart
+const EXIT_ERROR: i32 = 1;
+const EXIT_NOT_FOUND: i32 = 2;
+const EXIT_TIMEOUT: i32 = 3;
+const EXIT_VERIFY: i32 = 4;
+const EXIT_PERMISSION: i32 = 5;
+const EXIT_BUSY: i32 = 6;
+
+fn exit_code(err: &anyhow::Error) -> i32 {
+    for cause in err.chain() {
+        if let Some(e) = cause.downcast_ref::<PicoError>() {
+            return match e {
+                PicoError::NotFound(_) | PicoError::NoDevices => EXIT_NOT_FOUND,
+                PicoError::Timeout | PicoError::Disconnected => EXIT_TIMEOUT,
+                PicoError::VerifyMismatch(_) | PicoError::UploadIncomplete => EXIT_VERIFY,
+                PicoError::Permission(_) => EXIT_PERMISSION,
+                PicoError::Busy(_) => EXIT_BUSY,
+                _ => EXIT_ERROR,
+            };
+        }
+        // Serial and network failures below the protocol
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            use std::io::ErrorKind;
+            match e.kind() {
+                ErrorKind::TimedOut
+                | ErrorKind::BrokenPipe
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::UnexpectedEof => return EXIT_TIMEOUT,
+                ErrorKind::PermissionDenied => return EXIT_PERMISSION,
+                _ => {}
+            }
+        }
+    }
+    EXIT_ERROR
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(exit_code(&e));
+    }
+}
+
+fn run() -> Result<()> {
     let args = Cli::parse();
+    let log_level = match args.verbose {
+        0 | 1 => log::LevelFilter::Warn,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(log_level)
+        .format_timestamp_millis()
+        .parse_default_env()
+        .init();
+
+    if let Some(timeout) = args.response_timeout {
+        set_default_response_timeout(timeout);
+    }
+
+    let config = Config::load()?;
+    let color = args
+        .color
+        .or(config.defaults.color)
+        .unwrap_or(ColorChoice::Auto);
+    let ui = Ui::new(color, args.verbose > 0, args.quiet, args.json);
+    let devices = Devices {
+        baud: args.baud,
+        remote: args.remote,
+        default_device: config.defaults.device.clone(),
+    };
+    let rom_size =
+        |size: Option<RomSize>| size.or(config.defaults.size).unwrap_or(DEFAULT_ROM_SIZE);
+    // Like rom_size, but without a size the smallest one that holds the image
+    let image_rom_size = |size: Option<RomSize>, source: &Path| -> Result<RomSize> {
+        if let Some(size) = size.or(config.defaults.size) {
+            return Ok(size);
+        }
+        let len = read_image(source)?.len();
+        let size = RomSize::smallest_fitting(len)
+            .ok_or_else(|| anyhow!("{:?} ({} bytes) is larger than any ROM size", source, len))?;
+        ui.info(format!(
+            "Using a {} ROM for {:?} ({} bytes)",
+            size, source, len
+        ));
+        Ok(size)
+    };
 
     match args.command {
         Commands::List => {
-            let found = enumerate_picos()?;
-            if found.len() > 0 {
+            let found = devices.enumerate()?;
+            if ui.json {
+                let list: Vec<_> = found
+                    .iter()
+                    .map(|x| {
+                        serde_json::json!({
+                            "name": x.name,
+                            "path": x.path,
+                            "serial_number": x.serial_number,
+                        })
+                    })
+                    .collect();
+                ui.print_json(&list)?;
+            } else if !found.is_empty() {
                 println!("Available PicoROMs:");
-                for (k, v) in found.iter() {
-                    println!("  {:16} [{}]", k, v.path);
+                for info in found.iter() {
+                    match &info.serial_number {
+                        Some(serial) => {
+                            println!("  {:16} [{}] id:{}", info.name, info.path, serial)
+                        }
+                        None => println!("  {:16} [{}]", info.name, info.path),
+                    }
                 }
             } else {
                 println!("No PicoROMs found.");
             }
         }
-        Commands::Identify { name } => {
-            let mut pico = find_pico(&name)?;
+        Commands::Identify {
+            name,
+            repeat,
+            interval,
+        } => {
+            let name = devices.resolve(name)?;
+            let mut pico = devices.find(&name)?;
             pico.identify()?;
-            println!("Requested identification from '{}'", name);
+            ui.info(format!(
+                "Requested identification from {}",
+                ui.device(&name, &pico)
+            ));
+            if repeat {
+                let running = Arc::new(AtomicBool::new(true));
+                let handler_running = running.clone();
+                ctrlc::set_handler(move || handler_running.store(false, Ordering::SeqCst))?;
+                ui.info(format!(
+                    "Repeating every {}, press Ctrl-C to stop.",
+                    humantime::format_duration(interval)
+                ));
+                let mut next = Instant::now() + interval;
+                while running.load(Ordering::SeqCst) {
+                    if Instant::now() >= next {
+                        pico.identify()?;
+                        next += interval;
+                    }
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+            }
         }
         Commands::Commit { name } => {
-            let mut pico = find_pico(&name)?;
-            let spinner = ProgressBar::new_spinner()
-                .with_prefix("Storing to Flash")
-                .with_style(
-                    ProgressStyle::with_template("{prefix:.bold} {spinner} {msg}")
-                        .unwrap()
-                        .tick_chars(r"\|/--"),
-                );
-            spinner.enable_steady_tick(Duration::from_millis(250));
-            pico.commit_rom()?;
-            spinner.finish_with_message("Done.");
+            let name = devices.resolve(name)?;
+            let mut pico = devices.find(&name)?;
+            commit_with_spinner(&ui, &mut pico)?;
         }
-        Commands::Rename { current, new } => {
-            let mut pico = find_pico(&current)?;
-            pico.set_ident(&new)?;
-            println!("Renamed '{}' to '{}'", current, new);
+        Commands::Rename {
+            current,
+            new,
+            dry_run,
+        } => {
+            let mut pico = devices.find(&current)?;
+            if dry_run {
+                print_change("name", &pico.get_ident()?, &new);
+            } else if pico.set_ident(&new)? {
+                ui.info(format!(
+                    "Renamed {} to '{}'",
+                    ui.device(&current, &pico),
+                    new
+                ));
+            } else {
+                ui.info(format!(
+                    "{} is already named '{}'",
+                    ui.device(&current, &pico),
+                    new
+                ));
+            }
         }
         Commands::Upload {
             name,
             source,
             size,
             store,
+            exec,
+            check_unique,
+            if_changed,
+            verify,
+            reset_cycle,
+            reset_hold,
+            reset_release,
+        } => {
+            let size = image_rom_size(size, &source)?;
+            if check_unique {
+                if let Some(filename) = source.file_name() {
+                    warn_rom_name_in_use(&ui, &devices, &name, &filename.to_string_lossy())?;
+                }
+            }
+            let mut pico = devices.find(&name)?;
+            if reset_cycle {
+                pico.reset(reset_hold)?;
+            }
+            let result = (|| -> Result<()> {
+                if !upload_image(&ui, &mut pico, &source, size, if_changed, verify)? {
+                    ui.info(format!("{:?} unchanged.", source));
+                } else if ui.verbose {
+                    println!(
+                        "Uploaded {:?} as a {} ROM to {}",
+                        source,
+                        size,
+                        ui.device(&name, &pico)
+                    );
+                }
+                if store {
+                    commit_with_spinner(&ui, &mut pico)?;
+                }
+                Ok(())
+            })();
+            if reset_cycle {
+                pico.reset(reset_release)?;
+            }
+            result?;
+            if let Some(exec) = exec {
+                run_upload_hook(&exec, &name, size)?;
+            }
+        }
+        Commands::Watch {
+            name,
+            source,
+            size,
+            commit,
+            reset,
+            debounce,
         } => {
-            let mut pico = find_pico(&name)?;
-            let data = read_file(source.as_path(), size)?;
-            let progress = ProgressBar::new(data.len() as u64)
-                .with_prefix("Uploading ROM")
-                .with_style(
-                    ProgressStyle::with_template("{prefix:.bold} [{wide_bar:.cyan/blue}] {msg:10}")
-                        .unwrap()
-                        .progress_chars("#>-"),
+            let mut pico = devices.find(&name)?;
+            let upload = |pico: &mut PicoLink| -> Result<bool> {
+                let size = image_rom_size(size, &source)?;
+                if !upload_image(&ui, pico, &source, size, true, false)? {
+                    return Ok(false);
+                }
+                if commit {
+                    commit_with_spinner(&ui, pico)?;
+                }
+                Ok(true)
+            };
+
+            let running = Arc::new(AtomicBool::new(true));
+            let handler_running = running.clone();
+            ctrlc::set_handler(move || handler_running.store(false, Ordering::SeqCst))?;
+            ui.info(format!(
+                "Watching {:?} for {}, press Ctrl-C to stop.",
+                source,
+                ui.device(&name, &pico)
+            ));
+
+            // The first upload happens straight away, as the file has never been seen
+            let mut uploaded = None;
+            let mut pending: Option<((SystemTime, u64), Instant)> = None;
+            while running.load(Ordering::SeqCst) {
+                let stamp = file_stamp(&source);
+                match (stamp, pending) {
+                    (Some(stamp), _) if Some(stamp) == uploaded => pending = None,
+                    (Some(stamp), Some((seen, since)))
+                        if stamp == seen && (uploaded.is_none() || since.elapsed() >= debounce) =>
+                    {
+                        pending = None;
+                        uploaded = Some(stamp);
+                        let result = if reset {
+                            with_reset_held(&mut pico, upload)
+                        } else {
+                            upload(&mut pico)
+                        };
+                        let time = humantime::format_rfc3339_seconds(SystemTime::now());
+                        match result {
+                            Ok(true) => println!("[{}] Uploaded {} bytes", time, stamp.1),
+                            Ok(false) => println!("[{}] Unchanged, not uploaded", time),
+                            // Keep watching, the next save may well fix it
+                            Err(e) => eprintln!("[{}] Error: {:#}", time, e),
+                        }
+                    }
+                    (Some(stamp), _) => {
+                        if pending.is_none_or(|(seen, _)| seen != stamp) {
+                            pending = Some((stamp, Instant::now()));
+                        }
+                    }
+                    (None, _) => pending = None,
+                }
+                std::thread::sleep(WATCH_POLL);
+            }
+        }
+        Commands::UploadAll {
+            pattern,
+            dir,
+            size,
+            store,
+        } => upload_all(&ui, &devices, &pattern, &dir, size, store)?,
+        Commands::UploadPattern {
+            name,
+            pattern,
+            size,
+            store,
+        } => {
+            let size = rom_size(size);
+            let mut pico = devices.find(&name)?;
+            upload_pattern(&ui, &mut pico, pattern, size)?;
+            if ui.verbose {
+                println!(
+                    "Uploaded {} pattern as a {} ROM to {}",
+                    pattern.name(),
+                    size,
+                    ui.device(&name, &pico)
                 );
-            pico.upload(&data, size.mask(), |x| progress.inc(x as u64))?;
-            progress.finish_with_message("Done.");
-            if let Some(filename) = source.file_name() {
-                pico.set_parameter("rom_name", filename.to_string_lossy().as_ref())?;
             }
             if store {
-                let spinner = ProgressBar::new_spinner()
-                    .with_prefix("Storing to Flash")
-                    .with_style(
-                        ProgressStyle::with_template("{prefix:.bold} {spinner} {msg}")
-                            .unwrap()
-                            .tick_chars(r"\|/--"),
-                    );
-                spinner.enable_steady_tick(Duration::from_millis(250));
-                pico.commit_rom()?;
-                spinner.finish_with_message("Done.");
+                commit_with_spinner(&ui, &mut pico)?;
+            }
+        }
+        Commands::Patch {
+            name,
+            addr,
+            source,
+            skip,
+            len,
+            region,
+            store,
+        } => {
+            let data = if skip.is_some() || len.is_some() {
+                read_file_window(&source, skip.unwrap_or(0), len)?
+            } else {
+                fs::read(&source)?
+            };
+            let addr = match region {
+                Some(region) => {
+                    find_region(&config, &region)?
+                        .resolve(&region, addr, Some(data.len() as u32))?
+                        .0
+                }
+                None => addr,
+            };
+            let mut pico = devices.find(&name)?;
+            let end = addr as usize + data.len();
+            if end > pico.max_rom_size() {
+                return Err(anyhow!(
+                    "{:?} ({} bytes) at 0x{:x} extends past the end of the ROM",
+                    source,
+                    data.len(),
+                    addr
+                ));
+            }
+            let progress = ui.progress_bar(data.len() as u64, "Patching ROM");
+            let start = Instant::now();
+            // A patch only replaces part of the image, so rom_name is left as-is
+            pico.upload_to(addr, &data, |x| progress.inc(x as u64))?;
+            progress.finish_with_message("Done.");
+            ui.timing("Patch", start, Some(data.len()));
+            if store {
+                commit_range_with_spinner(&ui, &mut pico, addr, data.len() as u32)?;
+            }
+        }
+        Commands::Download {
+            name,
+            dest,
+            offset,
+            len,
+            region,
+            full,
+            snapshot,
+            hash,
+            format,
+        } => {
+            let (offset, len) = match region {
+                Some(region) => {
+                    let (offset, len) =
+                        find_region(&config, &region)?.resolve(&region, offset, len)?;
+                    (offset, Some(len))
+                }
+                None => (offset, len),
+            };
+            let mut pico = devices.find(&name)?;
+            let rom_size = if full {
+                pico.max_rom_size() as u64
+            } else {
+                pico.rom_size()? as u64
+            };
+            let len = len.map_or(rom_size.saturating_sub(offset as u64), |x| x as u64);
+            if offset as u64 + len > rom_size {
+                return Err(anyhow!(
+                    "Range 0x{:x}-0x{:x} is outside the ROM (0x{:x} bytes)",
+                    offset,
+                    offset as u64 + len,
+                    rom_size
+                ));
+            }
+            let format = format.unwrap_or_else(|| ImageFormat::from_path(&dest));
+            format.check_fits(&dest, offset as u64 + len)?;
+            let progress = ui.progress_bar(len, "Downloading ROM");
+            let start = Instant::now();
+            let read = |pico: &mut PicoLink| {
+                pico.download_from(offset, len as usize, |x| progress.inc(x as u64))
+            };
+            let data = if snapshot {
+                with_reset_held(&mut pico, read)?
+            } else {
+                read(&mut pico)?
+            };
+            progress.finish_with_message("Done.");
+            ui.timing("Download", start, Some(data.len()));
+            fs::write(&dest, format.encode(&data, offset))?;
+            ui.info(format!("Downloaded {} bytes to {:?}", data.len(), dest));
+            if hash {
+                print_hashes(&data);
+            }
+        }
+        Commands::Verify { name, source, size } => {
+            let mut pico = devices.find(&name)?;
+            let size = current_rom_size(&mut pico, size)?;
+            let expected = read_file(&source, size, size.bytes())?;
+            verify_rom(&ui, &mut pico, &expected)?;
+            ui.info(format!("Verified {} bytes OK", expected.len()));
+        }
+        Commands::Checksum { name, source, size } => {
+            let mut pico = devices.find(&name)?;
+            let size = current_rom_size(&mut pico, size)?;
+            let crc = rom_checksum(&ui, &mut pico, size.bytes())?;
+            println!("CRC32: {:08x}", crc);
+            if let Some(source) = source {
+                let expected = crc32fast::hash(&read_file(&source, size, size.bytes())?);
+                if crc != expected {
+                    return Err(PicoError::VerifyMismatch(format!(
+                        "ROM CRC32 {:08x} doesn't match {:?} ({:08x})",
+                        crc, source, expected
+                    ))
+                    .into());
+                }
+                ui.info(format!("Matches {:?}", source));
+            }
+        }
+        Commands::Read { name, offset, len } => {
+            let mut pico = devices.find(&name)?;
+            let progress = ui.progress_bar(len as u64, "Reading ROM");
+            let data = pico.read_range(offset, len as usize, |x| progress.inc(x as u64))?;
+            progress.finish_and_clear();
+            for line in verify::hexdump(&data, offset) {
+                println!("{}", line);
+            }
+        }
+        Commands::Info { name } => {
+            let name = devices.resolve(name)?;
+            let info = devices
+                .enumerate()?
+                .into_iter()
+                .find(|x| x.matches(&name))
+                .ok_or(PicoError::NotFound(name))?;
+            let mut pico = devices.open(&info)?;
+            print_device_info(&info, &mut pico);
+        }
+        Commands::FlashInfo { name } => {
+            let name = devices.resolve(name)?;
+            let mut pico = devices.find(&name)?;
+            print_flash_info(&name, &mut pico)?;
+        }
+        Commands::Provision {
+            name,
+            image,
+            size,
+            params,
+            commit,
+        } => {
+            let mut pico = devices.find(&name)?;
+            let total = image.is_some() as usize + params.len() + commit as usize;
+            let mut step = 0;
+            let mut next_step = |desc: String| {
+                step += 1;
+                format!("Step {}/{} ({}) failed", step, total, desc)
+            };
+
+            if let Some(image) = &image {
+                let failed = next_step(format!("upload {:?}", image));
+                let size = image_rom_size(size, image).context(failed.clone())?;
+                upload_image(&ui, &mut pico, image, size, false, false).context(failed)?;
+            }
+            for (param, value) in &params {
+                let failed = next_step(format!("set {}={}", param, value));
+                let newvalue = pico.set_parameter(param, value).context(failed)?;
+                ui.info(format!("{}={}", param, newvalue));
             }
+            if commit {
+                let failed = next_step("commit".to_string());
+                commit_with_spinner(&ui, &mut pico).context(failed)?;
+            }
+            ui.info(format!("Provisioned {}", ui.device(&name, &pico)));
+        }
+        Commands::Status { name } => {
+            let name = devices.resolve(name)?;
+            let mut pico = devices.find(&name)?;
+            print_status(&name, &mut pico);
+        }
+        Commands::Clear { name, value } => {
+            let name = devices.resolve(name)?;
+            let mut pico = devices.find(&name)?;
+            let data = vec![value; pico.rom_size()?];
+            let progress = ui.progress_bar(data.len() as u64, "Clearing ROM");
+            pico.upload(&data, |x| progress.inc(x as u64))?;
+            progress.finish_with_message("Done.");
+            ui.info(format!(
+                "Filled {} bytes of {} with 0x{:02x}",
+                data.len(),
+                ui.device(&name, &pico),
+                value
+            ));
+        }
+        Commands::Comms {
+            name,
+            addr,
+            exchanges,
+            timeout,
+            reset_between,
+            reset_pulse,
+        } => {
+            let mut pico = devices.find(&name)?;
+            pico.start_comms(addr)?;
+            let result = (|| -> Result<()> {
+                for (idx, data) in exchanges.iter().enumerate() {
+                    if reset_between && idx > 0 {
+                        pulse_reset(&mut pico, reset_pulse)?;
+                    }
+                    println!("> {}", format_hex(&data.0));
+                    let response = comms_exchange(&mut pico, &data.0, timeout)?;
+                    println!("< {}", format_hex(&response));
+                }
+                Ok(())
+            })();
+            // Always end comms, even if an exchange failed
+            pico.end_comms()?;
+            result?;
+        }
+        Commands::Commstest {
+            name,
+            addr,
+            len,
+            timeout,
+        } => {
+            let mut pico = devices.find(&name)?;
+            pico.start_comms(addr)?;
+            let result = comms_loopback_test(&ui, &mut pico, len as usize, timeout);
+            // Always end comms, even if the test failed
+            pico.end_comms()?;
+            result?;
+        }
+        Commands::Pipe { name, addr } => {
+            let mut pico = devices.find(&name)?;
+            let running = Arc::new(AtomicBool::new(true));
+            let handler_running = running.clone();
+            ctrlc::set_handler(move || handler_running.store(false, Ordering::SeqCst))?;
+            pico.start_comms(addr)?;
+            let result = comms_pipe(&mut pico, &running);
+            // Always end comms, even if forwarding failed
+            pico.end_comms()?;
+            result?;
         }
         Commands::Reset { name, level } => {
-            let mut pico = find_pico(&name)?;
-            pico.set_parameter("reset", &level)?;
-            println!("Setting '{}' reset pin to: {}", name, level);
+            let mut pico = devices.find(&name)?;
+            pico.reset(level)?;
+            ui.info(format!(
+                "Setting {} reset pin to: {}",
+                ui.device(&name, &pico),
+                level
+            ));
+        }
+        Commands::TestReset { name, pause } => {
+            let mut pico = devices.find(&name)?;
+            println!("Testing the reset pin of {}", ui.device(&name, &pico));
+            test_reset(&mut pico, pause)?;
         }
         Commands::Get { name, param } => {
-            let mut pico = find_pico(&name)?;
-            if let Some(param) = param {
+            let mut pico = devices.find(&name)?;
+            if ui.json {
+                let values = match param {
+                    Some(param) => {
+                        let value = pico.get_parameter(&param)?;
+                        BTreeMap::from([(param, value)])
+                    }
+                    None => read_parameters(&mut pico)?,
+                };
+                ui.print_json(&values)?;
+            } else if let Some(param) = param {
                 let value = pico.get_parameter(&param)?;
-                println!("{}={}", param, value);
+                if ui.quiet {
+                    println!("{}", value);
+                } else {
+                    println!("{}={}", param, value);
+                }
             } else {
                 let params = pico.get_parameters()?;
                 for p in params {
@@ -201,17 +2060,131 @@ fn main() -> Result<()> {
                 }
             }
         }
-        Commands::Set { name, param, value } => {
-            let mut pico = find_pico(&name)?;
-            let newvalue = pico.set_parameter(&param, &value)?;
-            println!("{}={}", param, newvalue);
+        Commands::ExportParams { name, file } => {
+            let mut pico = devices.find(&name)?;
+            let count = export_parameters(&mut pico, &file)?;
+            ui.info(format!("Exported {} parameters to {:?}", count, file));
+        }
+        Commands::ImportParams {
+            name,
+            file,
+            exclude,
+            commit,
+        } => {
+            let mut pico = devices.find(&name)?;
+            let skipped = import_parameters(&ui, &mut pico, &file, &exclude)?;
+            if !skipped.is_empty() {
+                ui.info(format!(
+                    "Skipped read-only parameters: {}",
+                    skipped.join(", ")
+                ));
+            }
+            if commit {
+                commit_with_spinner(&ui, &mut pico)?;
+            }
+        }
+        Commands::Clone {
+            src,
+            dst,
+            include_name,
+            store,
+        } => {
+            if src == dst {
+                return Err(anyhow!("Source and destination are both '{}'", src));
+            }
+            let mut src_pico = devices.find(&src)?;
+            let mut dst_pico = devices.find(&dst)?;
+            clone_device(&ui, &mut src_pico, &mut dst_pico, include_name)?;
+            if store {
+                commit_with_spinner(&ui, &mut dst_pico)?;
+            }
+            ui.info(format!("Cloned '{}' to '{}'", src, dst));
+        }
+        Commands::DiffConfig { first, second, all } => {
+            let a = read_parameters(&mut devices.find(&first)?)?;
+            let b = read_parameters(&mut devices.find(&second)?)?;
+
+            let mut params: Vec<&String> = a.keys().chain(b.keys()).collect();
+            params.sort();
+            params.dedup();
+            params.retain(|x| all || !PER_DEVICE_PARAMS.contains(&x.as_str()));
+
+            let missing = "(missing)".to_string();
+            let differ: Vec<_> = params
+                .into_iter()
+                .map(|p| {
+                    (
+                        p,
+                        a.get(p).unwrap_or(&missing),
+                        b.get(p).unwrap_or(&missing),
+                    )
+                })
+                .filter(|(_, x, y)| x != y)
+                .collect();
+
+            if differ.is_empty() {
+                ui.info(format!(
+                    "'{}' and '{}' have the same parameters",
+                    first, second
+                ));
+            } else {
+                let width = differ.iter().map(|(_, x, _)| x.len()).max().unwrap_or(0);
+                let width = width.max(first.len());
+                println!("{:16} {:width$} {}", "parameter", first, second);
+                for (param, x, y) in differ {
+                    println!("{:16} {:width$} {}", param, x, y);
+                }
+            }
+        }
+        Commands::Set {
+            name,
+            param,
+            value,
+            dry_run,
+        } => {
+            let mut pico = devices.find(&name)?;
+            if dry_run {
+                print_change(&param, &pico.get_parameter(&param)?, &value);
+            } else {
+                let newvalue = pico.set_parameter(&param, &value)?;
+                ui.info(format!("{}={}", param, newvalue));
+            }
         }
 
         Commands::USBBoot { name } => {
-            let mut pico = find_pico(&name)?;
-            println!("Requesting USB boot");
+            let name = devices.resolve(name)?;
+            let mut pico = devices.find(&name)?;
+            ui.info("Requesting USB boot");
             pico.usb_boot()?;
         }
+        Commands::Serve { listen } => {
+            if devices.remote.is_some() {
+                return Err(anyhow!("serve can't be used with --remote"));
+            }
+            let listener = std::net::TcpListener::bind(&listen)
+                .with_context(|| format!("Could not listen on {}", listen))?;
+            println!("Serving PicoROMs on {}", listener.local_addr()?);
+            for stream in listener.incoming() {
+                // Accept errors such as ECONNABORTED or EMFILE only affect one client
+                let (peer, stream) = match stream.and_then(|x| Ok((x.peer_addr()?, x))) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        ui.warn(format!("Could not accept a connection: {}", e));
+                        std::thread::sleep(ACCEPT_RETRY_WAIT);
+                        continue;
+                    }
+                };
+                if ui.verbose {
+                    println!("Connection from {}", peer);
+                }
+                let baud = devices.baud;
+                std::thread::spawn(move || {
+                    if let Err(e) = serve_client(stream, baud) {
+                        log::warn!("{}: {}", peer, e);
+                    }
+                });
+            }
+        }
     }
 
     Ok(())
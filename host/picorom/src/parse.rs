@@ -0,0 +1,129 @@
+use anyhow::{anyhow, Result};
+
+/// Parse an address or size given as decimal or 0x prefixed hex,
+/// with an optional k (1024) or M (1024 * 1024) suffix, e.g. `0x1f000` or `256k`.
+pub fn parse_addr(s: &str) -> Result<u32> {
+    let trimmed = s.trim();
+    let (number, scale) = if let Some(x) = trimmed.strip_suffix(['k', 'K']) {
+        (x, 1024)
+    } else if let Some(x) = trimmed.strip_suffix(['m', 'M']) {
+        (x, 1024 * 1024)
+    } else {
+        (trimmed, 1)
+    };
+
+    let value = match number
+        .strip_prefix("0x")
+        .or_else(|| number.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => number.parse::<u32>(),
+    }
+    .map_err(|_| anyhow!("invalid number '{}'", s))?;
+
+    value
+        .checked_mul(scale)
+        .ok_or_else(|| anyhow!("'{}' is too large", s))
+}
+
+/// Parse a byte count, using the same formats as `parse_addr`
+pub fn parse_size(s: &str) -> Result<u32> {
+    parse_addr(s)
+}
+
+/// Parse a single byte value, decimal or 0x prefixed hex
+pub fn parse_byte(s: &str) -> Result<u8> {
+    let value = parse_addr(s)?;
+    u8::try_from(value).map_err(|_| anyhow!("'{}' does not fit in a byte", s))
+}
+
+/// Bytes given on the command line as hex
+#[derive(Clone, Debug)]
+pub struct HexBytes(pub Vec<u8>);
+
+/// Parse hex bytes, optionally separated by spaces, e.g. `01 02 ff` or `0102ff`
+pub fn parse_hex_bytes(s: &str) -> Result<HexBytes> {
+    let digits: Vec<char> = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(anyhow!("'{}' has an odd number of hex digits", s));
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let byte: String = pair.iter().collect();
+            u8::from_str_radix(&byte, 16).map_err(|_| anyhow!("invalid hex byte '{}'", byte))
+        })
+        .collect::<Result<Vec<u8>>>()
+        .map(HexBytes)
+}
+
+/// Format bytes as space separated hex
+pub fn format_hex(data: &[u8]) -> String {
+    data.iter()
+        .map(|x| format!("{:02x}", x))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addr_prefixes_and_suffixes() {
+        assert_eq!(parse_addr("4096").unwrap(), 4096);
+        assert_eq!(parse_addr("0x1f000").unwrap(), 0x1f000);
+        assert_eq!(parse_addr("0X1F000").unwrap(), 0x1f000);
+        assert_eq!(parse_addr("124k").unwrap(), 124 * 1024);
+        assert_eq!(parse_addr("2M").unwrap(), 2 * 1024 * 1024);
+        // The suffix scales hex too
+        assert_eq!(parse_addr("0x1fk").unwrap(), 0x1f * 1024);
+        assert_eq!(parse_addr(" 0x10 ").unwrap(), 0x10);
+    }
+
+    #[test]
+    fn addr_rejects_bad_input() {
+        assert!(parse_addr("").is_err());
+        assert!(parse_addr("0x").is_err());
+        assert!(parse_addr("k").is_err());
+        assert!(parse_addr("-1").is_err());
+        assert!(parse_addr("1.5k").is_err());
+        assert!(parse_addr("0x1g").is_err());
+        assert!(parse_addr("1 k").is_err());
+    }
+
+    #[test]
+    fn addr_overflow() {
+        assert_eq!(parse_addr("4095M").unwrap(), 4095 * 1024 * 1024);
+        assert!(parse_addr("4096M").is_err());
+        assert!(parse_addr("4194304k").is_err());
+        assert!(parse_addr("0x100000000").is_err());
+    }
+
+    #[test]
+    fn byte_range() {
+        assert_eq!(parse_byte("0xff").unwrap(), 0xff);
+        assert_eq!(parse_byte("0").unwrap(), 0);
+        assert!(parse_byte("256").is_err());
+        assert!(parse_byte("1k").is_err());
+    }
+
+    #[test]
+    fn hex_bytes() {
+        assert_eq!(parse_hex_bytes("01 02 ff").unwrap().0, [0x01, 0x02, 0xff]);
+        assert_eq!(parse_hex_bytes("0102FF").unwrap().0, [0x01, 0x02, 0xff]);
+        assert_eq!(parse_hex_bytes(" 0 1\t02 ").unwrap().0, [0x01, 0x02]);
+        assert!(parse_hex_bytes("").unwrap().0.is_empty());
+        assert!(parse_hex_bytes("010").is_err());
+        assert!(parse_hex_bytes("0g").is_err());
+        assert!(parse_hex_bytes("0x01").is_err());
+    }
+
+    #[test]
+    fn hex_format_round_trips() {
+        let data = [0x00, 0x7f, 0xff];
+        assert_eq!(format_hex(&data), "00 7f ff");
+        assert_eq!(parse_hex_bytes(&format_hex(&data)).unwrap().0, data);
+    }
+}
@@ -0,0 +1,35 @@
+use clap::ValueEnum;
+
+/// Position dependent test images, so reading any location shows which
+/// address the device actually served
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Pattern {
+    /// Each little-endian 32-bit word holds its own byte address
+    Addr32,
+    /// Each byte holds the low 8 bits of its address
+    Ramp,
+    /// Alternating 0x55 and 0xaa bytes
+    Checker,
+}
+
+impl Pattern {
+    /// Generate `len` bytes of the pattern, starting at address 0
+    pub fn generate(&self, len: usize) -> Vec<u8> {
+        match self {
+            Pattern::Addr32 => (0..len)
+                .step_by(4)
+                .flat_map(|addr| (addr as u32).to_le_bytes())
+                .take(len)
+                .collect(),
+            Pattern::Ramp => (0..len).map(|addr| addr as u8).collect(),
+            Pattern::Checker => (0..len)
+                .map(|addr| if addr % 2 == 0 { 0x55 } else { 0xaa })
+                .collect(),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.to_possible_value()
+            .map_or_else(String::new, |x| x.get_name().to_string())
+    }
+}
@@ -0,0 +1,71 @@
+use anyhow::{anyhow, Context, Result};
+
+use crate::config::Config;
+use crate::parse::parse_addr;
+
+/// A named part of the ROM image, from `start` up to but not including `end`
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl Region {
+    /// Turn an `offset` and optional `len` within the region into an absolute
+    /// address and length, checking the result stays inside the region
+    pub fn resolve(&self, name: &str, offset: u32, len: Option<u32>) -> Result<(u32, u32)> {
+        let size = self.end - self.start;
+        let len = len.unwrap_or(size.saturating_sub(offset));
+        if offset as u64 + len as u64 > size as u64 {
+            return Err(anyhow!(
+                "0x{:x} bytes at offset 0x{:x} don't fit in region '{}' (0x{:x} bytes)",
+                len,
+                offset,
+                name,
+                size
+            ));
+        }
+        Ok((self.start + offset, len))
+    }
+}
+
+fn parse_region(s: &str) -> Result<Region> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_else(|| anyhow!("expected start..end, got '{}'", s))?;
+    let start = parse_addr(start)?;
+    let end = parse_addr(end)?;
+    if end <= start {
+        return Err(anyhow!("'{}' ends before it starts", s));
+    }
+    Ok(Region { start, end })
+}
+
+/// Look up a region defined in the config file
+pub fn find_region(config: &Config, name: &str) -> Result<Region> {
+    let path = config
+        .path
+        .as_ref()
+        .ok_or_else(|| anyhow!("No config directory to load regions from"))?;
+
+    match config.regions.get(name) {
+        Some(range) => {
+            parse_region(range).with_context(|| format!("Invalid region '{}' in {:?}", name, path))
+        }
+        None if config.regions.is_empty() => Err(anyhow!(
+            "Region '{}' not found, {:?} defines none",
+            name,
+            path
+        )),
+        None => Err(anyhow!(
+            "Region '{}' not found, defined regions are: {}",
+            name,
+            config
+                .regions
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
@@ -1,4 +1,8 @@
+use anyhow::{anyhow, Error};
 use clap::{builder::PossibleValue, ValueEnum};
+use serde::{de, Deserialize, Deserializer};
+use std::fmt;
+use std::str::FromStr;
 
 #[derive(Clone, Debug, Copy)]
 pub enum RomSize {
@@ -17,11 +21,76 @@ impl RomSize {
     pub fn mask(&self) -> u32 {
         (self.bytes() as u32) - 1
     }
+
+    /// The size with exactly `bytes` bytes, if there is one
+    pub fn from_bytes(bytes: usize) -> Option<RomSize> {
+        Self::value_variants()
+            .iter()
+            .copied()
+            .find(|x| x.bytes() == bytes)
+    }
+
+    /// The smallest size that holds `bytes` bytes, if any is large enough
+    pub fn smallest_fitting(bytes: usize) -> Option<RomSize> {
+        Self::value_variants()
+            .iter()
+            .copied()
+            .filter(|x| x.bytes() >= bytes)
+            .min_by_key(|x| x.bytes())
+    }
+
+    /// Sizes that fit in a ROM buffer of `max_bytes`, largest first
+    pub fn supported(max_bytes: usize) -> impl Iterator<Item = RomSize> {
+        Self::value_variants()
+            .iter()
+            .copied()
+            .filter(move |x| x.bytes() <= max_bytes)
+    }
+}
+
+impl fmt::Display for RomSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomSize::MBit(x) => write!(f, "{}MBit", x),
+            RomSize::KBit(x) => write!(f, "{}KBit", x),
+        }
+    }
+}
+
+/// Parses the same names as the command line, ignoring case, e.g. "2MBit" or "512kbit"
+impl FromStr for RomSize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::value_variants()
+            .iter()
+            .find(|x| x.to_string().eq_ignore_ascii_case(s.trim()))
+            .copied()
+            .ok_or_else(|| {
+                let names: Vec<String> = Self::value_variants()
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect();
+                anyhow!(
+                    "Invalid ROM size '{}', expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+impl<'de> Deserialize<'de> for RomSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
 }
 
 impl ValueEnum for RomSize {
     fn value_variants<'a>() -> &'a [Self] {
         &[
+            RomSize::MBit(4),
             RomSize::MBit(2),
             RomSize::MBit(1),
             RomSize::KBit(512),
@@ -35,9 +104,24 @@ impl ValueEnum for RomSize {
     }
 
     fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
-        match self {
-            RomSize::MBit(x) => Some(PossibleValue::new(format!("{}MBit", x))),
-            RomSize::KBit(x) => Some(PossibleValue::new(format!("{}KBit", x))),
-        }
+        Some(PossibleValue::new(self.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smallest_fitting_rounds_up() {
+        let fitting = |bytes| RomSize::smallest_fitting(bytes).map(|x| x.to_string());
+        assert_eq!(fitting(0).as_deref(), Some("8KBit"));
+        assert_eq!(fitting(200).as_deref(), Some("8KBit"));
+        assert_eq!(fitting(1024).as_deref(), Some("8KBit"));
+        assert_eq!(fitting(1025).as_deref(), Some("16KBit"));
+        assert_eq!(fitting(64 * 1024).as_deref(), Some("512KBit"));
+        assert_eq!(fitting(256 * 1024 + 1).as_deref(), Some("4MBit"));
+        assert_eq!(fitting(512 * 1024).as_deref(), Some("4MBit"));
+        assert_eq!(fitting(512 * 1024 + 1), None);
     }
 }
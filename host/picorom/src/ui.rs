@@ -0,0 +1,262 @@
+use clap::ValueEnum;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle, TermLike};
+use picolink::PicoLink;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// When to use colored output
+#[derive(Clone, Copy, Debug, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+/// Output settings shared by all commands
+pub struct Ui {
+    color: bool,
+    /// Whether stderr is a terminal that progress bars can redraw in place
+    interactive: bool,
+    pub verbose: bool,
+    /// Only print results and errors, no progress or informational messages
+    pub quiet: bool,
+    /// Print results as JSON, for commands that support it
+    pub json: bool,
+    /// Progress bars that are drawn together, see `multi_progress`
+    multi: MultiProgress,
+}
+
+impl Ui {
+    /// `Auto` enables color when stderr is a terminal and NO_COLOR is not set.
+    pub fn new(color: ColorChoice, verbose: bool, quiet: bool, json: bool) -> Ui {
+        let color = match color {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                std::env::var_os("NO_COLOR").is_none_or(|x| x.is_empty())
+                    && std::io::stderr().is_terminal()
+            }
+        };
+
+        console::set_colors_enabled(color);
+        console::set_colors_enabled_stderr(color);
+
+        let interactive = std::io::stderr().is_terminal();
+        // Redrawing the group needs a terminal, so otherwise it is hidden
+        let multi = if quiet || !interactive {
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+        } else {
+            MultiProgress::new()
+        };
+
+        Ui {
+            color,
+            interactive,
+            verbose,
+            quiet,
+            json,
+            multi,
+        }
+    }
+
+    /// Print a result as pretty printed JSON
+    pub fn print_json<T: Serialize>(&self, value: &T) -> anyhow::Result<()> {
+        println!("{}", serde_json::to_string_pretty(value)?);
+        Ok(())
+    }
+
+    /// Print an informational message, unless quiet
+    pub fn info<T: fmt::Display>(&self, msg: T) {
+        if !self.quiet {
+            println!("{}", msg);
+        }
+    }
+
+    /// Print a warning to stderr, even when quiet. Bars in the `multi_progress`
+    /// group are cleared while it prints, so the warning doesn't tear them.
+    pub fn warn<T: fmt::Display>(&self, msg: T) {
+        self.multi.suspend(|| eprintln!("Warning: {}", msg));
+    }
+
+    /// Quote a device name for messages, adding the port it was opened on when verbose
+    pub fn device(&self, name: &str, pico: &PicoLink) -> String {
+        if self.verbose {
+            format!("'{}' [{}]", name, pico.path)
+        } else {
+            format!("'{}'", name)
+        }
+    }
+
+    /// When verbose, report how long an operation took since `start`,
+    /// with the transfer rate if it moved `bytes`
+    pub fn timing(&self, operation: &str, start: Instant, bytes: Option<usize>) {
+        if !self.verbose {
+            return;
+        }
+
+        let elapsed = start.elapsed();
+        match bytes {
+            Some(bytes) => println!(
+                "{} completed in {:.1}s ({:.0} KB/s)",
+                operation,
+                elapsed.as_secs_f64(),
+                bytes as f64 / 1024.0 / elapsed.as_secs_f64().max(0.001)
+            ),
+            None => println!("{} completed in {:.1}s", operation, elapsed.as_secs_f64()),
+        }
+    }
+
+    /// Progress of a transfer of `len` bytes, showing the rate and time left
+    /// so that a stalled transfer is obvious
+    pub fn progress_bar(&self, len: u64, prefix: impl Into<Cow<'static, str>>) -> ProgressBar {
+        let template = if !self.interactive {
+            "{prefix} {percent}% {msg}"
+        } else if self.color {
+            "{prefix:.bold} [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta}) {msg:10}"
+        } else {
+            "{prefix} [{wide_bar}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta}) {msg:10}"
+        };
+
+        let bar = ProgressBar::new(len).with_prefix(prefix).with_style(
+            ProgressStyle::with_template(template)
+                .unwrap()
+                .progress_chars("#>-"),
+        );
+        if self.quiet {
+            bar.set_draw_target(ProgressDrawTarget::hidden());
+        } else if !self.interactive {
+            bar.set_draw_target(LineTarget::draw_target());
+        }
+        bar
+    }
+
+    /// Group of progress bars drawn together, one per line. Only drawn on a
+    /// terminal, and shared so that `warn` can clear it while printing.
+    pub fn multi_progress(&self) -> MultiProgress {
+        self.multi.clone()
+    }
+
+    pub fn spinner(&self, prefix: &'static str) -> ProgressBar {
+        let template = if !self.interactive {
+            "{prefix} {msg}"
+        } else if self.color {
+            "{prefix:.bold} {spinner} {msg}"
+        } else {
+            "{prefix} {spinner} {msg}"
+        };
+
+        let spinner = ProgressBar::new_spinner().with_prefix(prefix).with_style(
+            ProgressStyle::with_template(template)
+                .unwrap()
+                .tick_chars(r"\|/--"),
+        );
+        if self.quiet {
+            spinner.set_draw_target(ProgressDrawTarget::hidden());
+        } else if !self.interactive {
+            spinner.set_draw_target(LineTarget::draw_target());
+        } else {
+            spinner.enable_steady_tick(Duration::from_millis(250));
+        }
+        spinner
+    }
+}
+
+/// Progress output for logs and pipes: redraws become lines on stderr, at most
+/// one a second. The final state is printed when the bar is dropped.
+#[derive(Debug, Default)]
+struct LineTarget {
+    state: Mutex<LineState>,
+}
+
+#[derive(Debug, Default)]
+struct LineState {
+    /// The line being drawn
+    current: String,
+    /// The last complete line drawn, if it hasn't been printed yet
+    pending: Option<String>,
+    printed: String,
+    printed_at: Option<Instant>,
+}
+
+impl LineState {
+    fn print_pending(&mut self) -> io::Result<()> {
+        if let Some(line) = self.pending.take() {
+            writeln!(io::stderr(), "{}", line)?;
+            self.printed = line;
+            self.printed_at = Some(Instant::now());
+        }
+        Ok(())
+    }
+}
+
+impl LineTarget {
+    fn draw_target() -> ProgressDrawTarget {
+        ProgressDrawTarget::term_like(Box::new(LineTarget::default()))
+    }
+}
+
+impl Drop for LineTarget {
+    fn drop(&mut self) {
+        let _ = self.state.lock().unwrap().print_pending();
+    }
+}
+
+impl TermLike for LineTarget {
+    fn width(&self) -> u16 {
+        80
+    }
+
+    fn move_cursor_up(&self, _n: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_down(&self, _n: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_right(&self, _n: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_left(&self, _n: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_line(&self, s: &str) -> io::Result<()> {
+        self.write_str(s)?;
+        self.flush()
+    }
+
+    fn write_str(&self, s: &str) -> io::Result<()> {
+        self.state.lock().unwrap().current.push_str(s);
+        Ok(())
+    }
+
+    fn clear_line(&self) -> io::Result<()> {
+        self.state.lock().unwrap().current.clear();
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let line = state.current.trim().to_string();
+        state.current.clear();
+        if line.is_empty() || line == state.printed {
+            return Ok(());
+        }
+
+        state.pending = Some(line);
+        if state
+            .printed_at
+            .is_none_or(|x| x.elapsed() >= Duration::from_secs(1))
+        {
+            state.print_pending()?;
+        }
+        Ok(())
+    }
+}
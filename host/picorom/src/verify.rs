@@ -0,0 +1,83 @@
+/// Bytes shown on each hexdump row
+const ROW_LEN: usize = 16;
+
+/// Rows of context shown either side of a mismatch
+const CONTEXT_ROWS: usize = 1;
+
+/// Offset of the first byte that differs between `expected` and `actual`,
+/// comparing only the bytes both have
+pub fn first_mismatch(expected: &[u8], actual: &[u8]) -> Option<usize> {
+    expected.iter().zip(actual).position(|(a, b)| a != b)
+}
+
+/// Number of differing bytes, comparing only the bytes both have
+pub fn count_mismatches(expected: &[u8], actual: &[u8]) -> usize {
+    expected.iter().zip(actual).filter(|(a, b)| a != b).count()
+}
+
+/// Hexdump rows of `data`, labelled with their address, `base` being the
+/// address of `data[0]`
+pub fn hexdump(data: &[u8], base: u32) -> Vec<String> {
+    data.chunks(ROW_LEN)
+        .enumerate()
+        .map(|(idx, chunk)| {
+            let bytes: Vec<String> = chunk.iter().map(|x| format!("{:02x}", x)).collect();
+            format!(
+                "0x{:06x}: {}",
+                base as usize + idx * ROW_LEN,
+                bytes.join(" ")
+            )
+        })
+        .collect()
+}
+
+/// Hexdump rows of `data` around `offset`, labelled as by `hexdump`
+pub fn hexdump_context(data: &[u8], offset: usize, base: u32) -> Vec<String> {
+    let row = offset / ROW_LEN;
+    let first = row.saturating_sub(CONTEXT_ROWS) * ROW_LEN;
+    let last = ((row + CONTEXT_ROWS + 1) * ROW_LEN).min(data.len());
+
+    hexdump(&data[first..last], base + first as u32)
+}
+
+/// Print where `actual` first differs from `expected`, with the surrounding
+/// bytes of both
+pub fn print_mismatch(expected: &[u8], actual: &[u8], offset: usize, base: u32) {
+    println!(
+        "First mismatch at 0x{:x}, {} bytes differ",
+        base as usize + offset,
+        count_mismatches(expected, actual)
+    );
+    println!("  expected:");
+    for line in hexdump_context(expected, offset, base) {
+        println!("    {}", line);
+    }
+    println!("  device:");
+    for line in hexdump_context(actual, offset, base) {
+        println!("    {}", line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_first_mismatch() {
+        assert_eq!(first_mismatch(&[1, 2, 3], &[1, 2, 3]), None);
+        assert_eq!(first_mismatch(&[1, 2, 3, 4], &[1, 0, 3, 0]), Some(1));
+        assert_eq!(count_mismatches(&[1, 2, 3, 4], &[1, 0, 3, 0]), 2);
+    }
+
+    #[test]
+    fn context_is_clamped_to_data() {
+        let data: Vec<u8> = (0..40).collect();
+        let rows = hexdump_context(&data, 2, 0x100);
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].starts_with("0x000100: 00 01 02"));
+
+        let rows = hexdump_context(&data, 39, 0);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1], "0x000020: 20 21 22 23 24 25 26 27");
+    }
+}
@@ -4,8 +4,9 @@ use std::collections::HashMap;
 
 use picolink::*;
 use pyo3::create_exception;
-use pyo3::exceptions::PyException;
+use pyo3::exceptions::{PyException, PyValueError};
 use pyo3::prelude::*;
+use pyo3::types::PyBytes;
 
 create_exception!(
     pypicorom,
@@ -21,67 +22,163 @@ create_exception!(
     "Communication timeout"
 );
 
+/// Check a Python style byteorder argument, returning true for big-endian
+fn big_endian(byteorder: &str) -> PyResult<bool> {
+    match byteorder {
+        "little" => Ok(false),
+        "big" => Ok(true),
+        _ => Err(PyValueError::new_err(
+            "byteorder must be either 'little' or 'big'",
+        )),
+    }
+}
+
+/// Convert a timeout in seconds from Python, rejecting negative and NaN values
+fn timeout_duration(secs: f32) -> PyResult<Duration> {
+    Duration::try_from_secs_f32(secs)
+        .map_err(|_| PyValueError::new_err(format!("invalid timeout {}", secs)))
+}
+
+/// How long an automatic reconnect waits for the device to come back
+const AUTO_RECONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether an error is the connection failing, which reconnecting may fix,
+/// rather than the device rejecting a request
+fn is_transport_error(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<PicoError>(),
+        Some(PicoError::Disconnected | PicoError::Timeout)
+    ) || e.downcast_ref::<std::io::Error>().is_some()
+}
+
 /// A PicoROM connection.
 #[pyclass]
 struct PicoROM {
-    link: PicoLink,
+    /// None while reconnecting, or after a reconnect failed
+    link: Option<PicoLink>,
     read_buffer: Vec<u8>,
-    comms_active: bool,
+    name: String,
+    /// Comms address to restart comms at after reconnecting
+    comms_addr: Option<u32>,
+    auto_reconnect: bool,
 }
 
 impl PicoROM {
+    /// The connection, or `PicoError::Disconnected` if the last reconnect failed
+    fn link(&mut self) -> anyhow::Result<&mut PicoLink> {
+        self.link
+            .as_mut()
+            .ok_or_else(|| PicoError::Disconnected.into())
+    }
+
+    fn link_comms_active(&self) -> bool {
+        self.link.as_ref().is_some_and(|x| x.comms_active())
+    }
+
     fn comms_inactive(&self) -> PyResult<()> {
-        if self.comms_active {
+        if self.link_comms_active() {
             return Err(CommsStateError::new_err("Comms active."));
         }
         Ok(())
     }
 
     fn comms_active(&self) -> PyResult<()> {
-        if !self.comms_active {
+        if !self.link_comms_active() {
             return Err(CommsStateError::new_err("Comms not active."));
         }
         Ok(())
     }
+
+    /// Find the device again by name, waiting up to `timeout` for it to reappear,
+    /// and restart comms if it was active
+    fn reopen(&mut self, timeout: Duration) -> anyhow::Result<()> {
+        // The port is opened exclusively, so the old handle has to be closed
+        // before the device can be opened again, even on the same path
+        drop(self.link.take());
+
+        let deadline = Instant::now() + timeout;
+        let link = loop {
+            match find_pico(&self.name) {
+                Ok(link) => break link,
+                Err(e) if Instant::now() >= deadline => return Err(e),
+                Err(_) => sleep(Duration::from_millis(100)),
+            }
+        };
+
+        let link = self.link.insert(link);
+        if let Some(addr) = self.comms_addr {
+            link.start_comms(addr)?;
+        }
+        Ok(())
+    }
+
+    /// Run a comms operation. If the connection fails and auto reconnect is
+    /// enabled, reconnect, then run it once more if `retry` or raise the error.
+    fn with_reconnect<T, F>(&mut self, retry: bool, f: F) -> PyResult<T>
+    where
+        F: Fn(&mut PicoLink) -> anyhow::Result<T>,
+    {
+        match f(self.link()?) {
+            Ok(x) => Ok(x),
+            Err(e) if self.auto_reconnect && is_transport_error(&e) => {
+                self.reopen(AUTO_RECONNECT_TIMEOUT)?;
+                if retry {
+                    Ok(f(self.link()?)?)
+                } else {
+                    Err(e.into())
+                }
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
 }
 
 #[pymethods]
 impl PicoROM {
+    /// Reconnect to the device after it has reset or been unplugged, waiting
+    /// up to `timeout` seconds for it to reappear. Comms is restarted if it was active.
+    #[pyo3(signature = (timeout=5.0), text_signature = "(timeout=5.0, /)")]
+    fn reconnect(&mut self, timeout: f32) -> PyResult<()> {
+        self.reopen(timeout_duration(timeout)?)?;
+        Ok(())
+    }
+
     /// Get the identifying name
     fn get_name(&mut self) -> PyResult<String> {
         self.comms_inactive()?;
 
-        Ok(self.link.get_ident()?)
+        Ok(self.link()?.get_ident()?)
     }
 
     /// Set the identifying name
     fn set_name(&mut self, name: String) -> PyResult<()> {
         self.comms_inactive()?;
 
-        Ok(self.link.set_ident(&name)?)
+        self.link()?.set_ident(&name)?;
+        Ok(())
     }
 
     /// Commit the current ROM data to flash memory
     fn commit(&mut self) -> PyResult<()> {
         self.comms_inactive()?;
 
-        Ok(self.link.commit_rom()?)
+        Ok(self.link()?.commit_rom_verified()?)
     }
 
     /// Ask PicoROM to identify itself
     fn identify(&mut self) -> PyResult<()> {
         self.comms_inactive()?;
 
-        Ok(self.link.identify()?)
+        Ok(self.link()?.identify()?)
     }
 
     /// Get all parameters as a dict
     fn parameters(&mut self) -> PyResult<HashMap<String,String>> {
-        let parameters = self.link.get_parameters()?;
+        let parameters = self.link()?.get_parameters()?;
         let mut param_map = HashMap::new();
 
         for p in parameters {
-            let value = self.link.get_parameter(&p)?;
+            let value = self.link()?.get_parameter(&p)?;
             param_map.insert(p, value);
         }
 
@@ -90,12 +187,12 @@ impl PicoROM {
 
     /// Get a single named parameter
     fn get_parameter(&mut self, name: String) -> PyResult<String> {
-        Ok(self.link.get_parameter(&name)?)
+        Ok(self.link()?.get_parameter(&name)?)
     }
 
     /// Set a single named parameter
     fn set_parameter(&mut self, name: String, value: String) -> PyResult<String> {
-        Ok(self.link.set_parameter(&name, &value)?)
+        Ok(self.link()?.set_parameter(&name, &value)?)
     }
 
     /// Upload ROM data
@@ -103,26 +200,50 @@ impl PicoROM {
     fn upload(&mut self, data: &[u8], mask: u32) -> PyResult<()> {
         self.comms_inactive()?;
 
-        self.link.upload(data, mask, |_| {})?;
+        self.link()?.upload(data, |_| {})?;
+        self.link()?.set_addr_mask(mask)?;
 
         Ok(())
     }
 
+    /// Set the address mask
+    fn set_addr_mask(&mut self, mask: u32) -> PyResult<()> {
+        self.comms_inactive()?;
+
+        Ok(self.link()?.set_addr_mask(mask)?)
+    }
+
     /// Update to a specific address
     fn upload_to(&mut self, addr: u32, data: &[u8]) -> PyResult<()> {
         self.comms_inactive()?;
 
-        self.link.upload_to(addr, data, |_| {})?;
+        self.link()?.upload_to(addr, data, |_| {})?;
 
         Ok(())
     }
 
+    /// Read ROM data from the start of the ROM buffer
+    fn download(&mut self, size: usize, py: Python<'_>) -> PyResult<PyObject> {
+        self.comms_inactive()?;
+
+        let data = self.link()?.download(size, |_| {})?;
+        Ok(PyBytes::new(py, &data).into())
+    }
+
+    /// Read ROM data from a specific address
+    fn download_to(&mut self, addr: u32, size: usize, py: Python<'_>) -> PyResult<PyObject> {
+        self.comms_inactive()?;
+
+        let data = self.link()?.download_from(addr, size, |_| {})?;
+        Ok(PyBytes::new(py, &data).into())
+    }
+
     /// Start two-way communications
     fn start_comms(&mut self, addr: u32) -> PyResult<()> {
         self.comms_inactive()?;
 
-        self.link.send(ReqPacket::CommsStart(addr))?;
-        self.comms_active = true;
+        self.link()?.start_comms(addr)?;
+        self.comms_addr = Some(addr);
         self.read_buffer.clear();
         Ok(())
     }
@@ -131,21 +252,27 @@ impl PicoROM {
     fn end_comms(&mut self) -> PyResult<()> {
         self.comms_active()?;
 
-        self.link.send(ReqPacket::CommsEnd)?;
-        self.comms_active = false;
+        self.link()?.end_comms()?;
+        self.comms_addr = None;
         self.read_buffer.clear();
         Ok(())
     }
 
+    /// Whether two-way communications are active
+    #[getter]
+    fn is_comms_active(&self) -> bool {
+        self.link_comms_active()
+    }
+
     /// Read from the communication channel
     #[pyo3(signature = (size=-1), text_signature = "(size=-1, /)")]
     fn read(&mut self, size: i32) -> PyResult<Option<Vec<u8>>> {
         self.comms_active()?;
 
-        let new_data = self.link.poll_comms(None)?;
+        let new_data = self.with_reconnect(true, |link| link.poll_comms(None))?;
         self.read_buffer.extend_from_slice(&new_data);
 
-        if self.read_buffer.len() == 0 {
+        if self.read_buffer.is_empty() {
             return Ok(None);
         }
 
@@ -167,10 +294,16 @@ impl PicoROM {
     ) -> PyResult<Vec<u8>> {
         self.comms_active()?;
 
-        let end = timeout.map(|x| Instant::now() + Duration::from_secs_f32(x));
+        let end = timeout
+            .map(timeout_duration)
+            .transpose()?
+            .map(|x| Instant::now() + x);
 
         loop {
-            let new_data = self.link.poll_comms(None)?;
+            // Wait in short steps so Ctrl-C is still noticed
+            let new_data = self.with_reconnect(true, |link| {
+                link.poll_comms_blocking(Duration::from_millis(10))
+            })?;
             self.read_buffer.extend_from_slice(&new_data);
 
             if self.read_buffer.len() < size {
@@ -180,39 +313,161 @@ impl PicoROM {
                     }
                 }
                 py.check_signals()?;
-                sleep(Duration::from_micros(10));
             } else {
                 return Ok(self.read_buffer.drain(0..size).collect());
             }
         }
     }
 
-    /// Write to the communication channel
+    /// Read up to and including `delimiter` with an optional timeout.
+    /// On timeout the partial line is kept for the next read.
+    #[pyo3(
+        signature = (timeout=None, delimiter=b"\n".to_vec()),
+        text_signature = "(timeout=None, delimiter=b\"\\n\", /)"
+    )]
+    fn readline(
+        &mut self,
+        timeout: Option<f32>,
+        delimiter: Vec<u8>,
+        py: Python<'_>,
+    ) -> PyResult<Vec<u8>> {
+        self.comms_active()?;
+        if delimiter.is_empty() {
+            return Err(PyValueError::new_err("delimiter must not be empty"));
+        }
+
+        let end = timeout
+            .map(timeout_duration)
+            .transpose()?
+            .map(|x| Instant::now() + x);
+        // Where to resume searching, so a long line isn't rescanned on every poll
+        let mut searched = 0;
+
+        loop {
+            let found = self.read_buffer[searched..]
+                .windows(delimiter.len())
+                .position(|x| x == delimiter);
+            if let Some(pos) = found {
+                let line_end = searched + pos + delimiter.len();
+                return Ok(self.read_buffer.drain(0..line_end).collect());
+            }
+            searched = (self.read_buffer.len() + 1).saturating_sub(delimiter.len());
+
+            if let Some(end) = end {
+                if Instant::now() >= end {
+                    return Err(CommsTimeoutError::new_err("readline timeout"));
+                }
+            }
+            py.check_signals()?;
+
+            let new_data = self.with_reconnect(true, |link| {
+                link.poll_comms_blocking(Duration::from_millis(10))
+            })?;
+            self.read_buffer.extend_from_slice(&new_data);
+        }
+    }
+
+    /// Discard any unread data from the communication channel
+    fn flush(&mut self) -> PyResult<()> {
+        self.comms_active()?;
+
+        self.link()?.comms_flush()?;
+        self.read_buffer.clear();
+        Ok(())
+    }
+
+    /// Write to the communication channel.
+    /// With auto reconnect, a write that fails is not sent again, as part of it
+    /// may already have reached the target. The connection is restored for the
+    /// next call and the error is raised.
     fn write(&mut self, data: Vec<u8>) -> PyResult<usize> {
         self.comms_active()?;
 
         let len = data.len();
-        let new_data = self.link.poll_comms(Some(data))?;
+        let new_data = self.with_reconnect(false, |link| link.poll_comms(Some(data.clone())))?;
         self.read_buffer.extend_from_slice(&new_data);
         Ok(len)
     }
+
+    /// Read a 16-bit value with an optional timeout
+    #[pyo3(
+        signature = (timeout=None, byteorder="little"),
+        text_signature = "(timeout=None, byteorder=\"little\", /)"
+    )]
+    fn read_u16(&mut self, timeout: Option<f32>, byteorder: &str, py: Python<'_>) -> PyResult<u16> {
+        let big = big_endian(byteorder)?;
+        let bytes: [u8; 2] = self.read_exact(2, timeout, py)?.try_into().unwrap();
+        Ok(if big {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    /// Read a 32-bit value with an optional timeout
+    #[pyo3(
+        signature = (timeout=None, byteorder="little"),
+        text_signature = "(timeout=None, byteorder=\"little\", /)"
+    )]
+    fn read_u32(&mut self, timeout: Option<f32>, byteorder: &str, py: Python<'_>) -> PyResult<u32> {
+        let big = big_endian(byteorder)?;
+        let bytes: [u8; 4] = self.read_exact(4, timeout, py)?.try_into().unwrap();
+        Ok(if big {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    /// Write a 16-bit value
+    #[pyo3(
+        signature = (value, byteorder="little"),
+        text_signature = "(value, byteorder=\"little\", /)"
+    )]
+    fn write_u16(&mut self, value: u16, byteorder: &str) -> PyResult<usize> {
+        let bytes = if big_endian(byteorder)? {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        };
+        self.write(bytes.to_vec())
+    }
+
+    /// Write a 32-bit value
+    #[pyo3(
+        signature = (value, byteorder="little"),
+        text_signature = "(value, byteorder=\"little\", /)"
+    )]
+    fn write_u32(&mut self, value: u32, byteorder: &str) -> PyResult<usize> {
+        let bytes = if big_endian(byteorder)? {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        };
+        self.write(bytes.to_vec())
+    }
 }
 
 /// Enumerate all available PicoROMs
 #[pyfunction]
 fn enumerate() -> PyResult<Vec<String>> {
     let picos = enumerate_picos()?;
-    Ok(Vec::from_iter(picos.keys().cloned()))
+    Ok(picos.into_iter().map(|x| x.name).collect())
 }
 
 /// Open a connection to the named PicoROM.
+/// With `auto_reconnect`, comms reads and writes that lose the connection
+/// reconnect. Reads are then retried once; writes raise the error instead.
 #[pyfunction]
-fn open(name: &str) -> PyResult<PicoROM> {
+#[pyo3(signature = (name, auto_reconnect=false), text_signature = "(name, auto_reconnect=False, /)")]
+fn open(name: &str, auto_reconnect: bool) -> PyResult<PicoROM> {
     let pico = find_pico(name)?;
     Ok(PicoROM {
-        link: pico,
+        link: Some(pico),
         read_buffer: Vec::new(),
-        comms_active: false,
+        name: name.to_string(),
+        comms_addr: None,
+        auto_reconnect,
     })
 }
 